@@ -120,6 +120,72 @@ class AutopushConfig(object):
 
     statsd_host = attrib(default="localhost")  # type: str
     statsd_port = attrib(default=8125)  # type: int
+    # autopush_rs only: "statsd" or "prometheus"
+    metrics_backend = attrib(default="statsd")  # type: str
+
+    # Rust connection node only: intermediate chain for ssl.cert, and a JSON
+    # list of additional {"key", "cert", "chain"} certificates picked by SNI
+    ssl_chain = attrib(default=None)  # type: Optional[str]
+    ssl_sni_certs = attrib(default=None)  # type: Optional[str]
+    # Rust connection node only: verification of clients connecting to the
+    # router_ssl listener, by CA and/or a JSON {"name": [sha256, ...]} map of
+    # allowed certificate fingerprints (as for client_certs)
+    router_ssl_ca = attrib(default=None)  # type: Optional[str]
+    router_client_certs = attrib(default=None)  # type: Optional[str]
+    # Rust connection node only: comma separated websocket subprotocols
+    # clients may negotiate, and Origins allowed to connect (all if unset)
+    ws_subprotocols = attrib(
+        default="push-notification,push-notification-msgpack")  # type: str
+    allowed_origins = attrib(default=None)  # type: Optional[str]
+    # Rust connection node only: permessage-deflate compression offered to
+    # websocket clients, with the largest window (in bits) it may use,
+    # whether compression contexts are kept between messages, and the most
+    # bytes they may take up per connection (0 for no limit)
+    ws_deflate = attrib(default=False)  # type: bool
+    ws_deflate_window_bits = attrib(default=15)  # type: int
+    ws_deflate_context_takeover = attrib(default=False)  # type: bool
+    ws_deflate_memory = attrib(default=0)  # type: int
+    # Rust connection node only: bearer token required to create users
+    # through the RFC 8030 POST /subscribe, those resources being off
+    # without one
+    push_service_key = attrib(default=None)  # type: Optional[str]
+    # Rust connection node only: websocket input limits in bytes, and the
+    # most messages queued to a client before waiting on it (0 for no limit)
+    max_message_size = attrib(default=262144)  # type: int
+    max_frame_size = attrib(default=0)  # type: int
+    max_send_queue = attrib(default=100)  # type: int
+    # Rust connection node only: notifications buffered for a client before
+    # further ones are stored instead
+    client_queue_depth = attrib(default=64)  # type: int
+    # Rust connection node only: hand delivery receipts of notifications
+    # without a Push-Receipt URL to the store_receipt command
+    local_receipts = attrib(default=False)  # type: bool
+    # Rust connection node only: file notifications that failed to be stored
+    # are spooled to until storage is available again
+    spool_path = attrib(default=None)  # type: Optional[str]
+    # Rust connection node only: most bytes the spool may take up,
+    # notifications that don't fit being dropped (0 for no limit)
+    spool_max_bytes = attrib(default=0)  # type: int
+    # Rust connection node only: JSON retry policies of calls into Python by
+    # command ("default" only covering idempotent ones), and how many failing
+    # in a row turn away new clients for breaker_cooldown seconds (0 to never
+    # turn them away)
+    call_retries = attrib(
+        default='{"default": {"attempts": 3, "base_delay": 0.1, '
+                '"max_delay": 2}}')  # type: Optional[str]
+    # Rust connection node only: JSON seconds a call into Python may take by
+    # command, e.g. {"default": 30}
+    call_timeouts = attrib(
+        default='{"default": 30}')  # type: Optional[str]
+    # Rust connection node only: most calls waiting for a Python worker in
+    # each priority lane, new clients being turned away while interactive
+    # calls are at it (0 for no limit)
+    queue_capacity = attrib(default=1000)  # type: int
+    # Rust connection node only: how calls into Python are encoded, msgpack
+    # or json (easier to debug)
+    call_encoding = attrib(default="msgpack")  # type: str
+    breaker_threshold = attrib(default=50)  # type: int
+    breaker_cooldown = attrib(default=30.0)  # type: float
 
     datadog_api_key = attrib(default=None)  # type: Optional[str]
     datadog_app_key = attrib(default=None)  # type: Optional[str]
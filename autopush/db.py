@@ -89,6 +89,11 @@ if TYPE_CHECKING:  # pragma: nocover
 # Max DynamoDB record lifespan (~ 30 days)
 MAX_EXPIRY = 2592000  # pragma: nocover
 
+# Most requests a single BatchWriteItem call may carry, and how many times
+# the ones DynamoDB leaves unprocessed are resent
+BATCH_WRITE_SIZE = 25
+BATCH_WRITE_ATTEMPTS = 3
+
 # Typing
 T = TypeVar('T')  # noqa
 
@@ -557,6 +562,24 @@ class Message(object):
     def store_message(self, notification):
         # type: (WebPushNotification) -> None
         """Stores a WebPushNotification in the message table"""
+        self.table.put_item(Item=self._message_item(notification))
+
+    @track_provisioned
+    def store_messages(self, notifications):
+        # type: (List[WebPushNotification]) -> None
+        """Stores several WebPushNotifications in batched writes
+
+        A later notification replaces an earlier one with the same topic,
+        as storing them one by one would.
+
+        """
+        with self.table.batch_writer(
+                overwrite_by_pkeys=['uaid', 'chidmessageid']) as batch:
+            for notification in notifications:
+                batch.put_item(Item=self._message_item(notification))
+
+    def _message_item(self, notification):
+        # type: (WebPushNotification) -> Dict[str, Any]
         item = dict(
             uaid=hasher(notification.uaid.hex),
             chidmessageid=notification.sort_key,
@@ -570,7 +593,11 @@ class Message(object):
         )
         if notification.data:
             item['data'] = notification.data
-        self.table.put_item(Item=item)
+        if notification.urgency:
+            item['urgency'] = notification.urgency
+        if notification.receipt:
+            item['receipt'] = notification.receipt
+        return item
 
     @track_provisioned
     def delete_message(self, notification):
@@ -599,6 +626,57 @@ class Message(object):
                 })
         return True
 
+    @track_provisioned
+    def delete_messages(self, notifications):
+        # type: (List[WebPushNotification]) -> List[bool]
+        """Deletes several messages, returning whether each was deleted
+
+        A topic message may have been replaced by a newer one with the same
+        topic, so those are deleted one by one on the condition that they
+        haven't been. Batch writes can't carry that condition, but nothing
+        else needs it, so the rest are deleted in batch writes.
+
+        """
+        results = [False] * len(notifications)
+        batched = []  # type: List[Tuple[int, Dict[str, str]]]
+        for i, notification in enumerate(notifications):
+            if notification.topic:
+                results[i] = self.delete_message(notification)
+            else:
+                batched.append((i, {
+                    'uaid': hasher(notification.uaid.hex),
+                    'chidmessageid': notification.sort_key,
+                }))
+        for start in range(0, len(batched), BATCH_WRITE_SIZE):
+            chunk = batched[start:start + BATCH_WRITE_SIZE]
+            for i in self._batch_delete(chunk):
+                results[i] = True
+        return results
+
+    def _batch_delete(self, keys):
+        # type: (List[Tuple[int, Dict[str, str]]]) -> List[int]
+        """Deletes up to ``BATCH_WRITE_SIZE`` messages by key in a batch
+        write, returning the indexes paired with the keys that were"""
+        pending = keys
+        for _ in range(BATCH_WRITE_ATTEMPTS):
+            response = self.table.meta.client.batch_write_item(
+                RequestItems={
+                    self.table.name: [
+                        dict(DeleteRequest=dict(Key=key))
+                        for _, key in pending
+                    ]
+                })
+            unprocessed = [
+                request['DeleteRequest']['Key']
+                for request in response.get(
+                    'UnprocessedItems', {}).get(self.table.name, [])
+            ]
+            pending = [(i, key) for i, key in pending if key in unprocessed]
+            if not pending:
+                break
+        left = set(i for i, _ in pending)
+        return [i for i, _ in keys if i not in left]
+
     @track_provisioned
     def fetch_messages(
             self,
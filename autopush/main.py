@@ -367,6 +367,31 @@ class RustConnectionApplication(AutopushMultiService):
             auto_ping_timeout=ns.auto_ping_timeout,
             max_connections=ns.max_connections,
             close_handshake_timeout=ns.close_handshake_timeout,
+            metrics_backend=ns.metrics_backend,
+            ssl_chain=ns.ssl_chain,
+            ssl_sni_certs=ns.ssl_sni_certs,
+            router_ssl_ca=ns.router_ssl_ca,
+            router_client_certs=ns.router_client_certs,
+            ws_subprotocols=ns.ws_subprotocols,
+            allowed_origins=ns.allowed_origins,
+            ws_deflate=ns.ws_deflate,
+            ws_deflate_window_bits=ns.ws_deflate_window_bits,
+            ws_deflate_context_takeover=ns.ws_deflate_context_takeover,
+            ws_deflate_memory=ns.ws_deflate_memory,
+            push_service_key=ns.push_service_key,
+            max_message_size=ns.max_message_size,
+            max_frame_size=ns.max_frame_size,
+            max_send_queue=ns.max_send_queue,
+            client_queue_depth=ns.client_queue_depth,
+            local_receipts=ns.local_receipts,
+            spool_path=ns.spool_path,
+            spool_max_bytes=ns.spool_max_bytes,
+            call_retries=ns.call_retries,
+            call_timeouts=ns.call_timeouts,
+            queue_capacity=ns.queue_capacity,
+            call_encoding=ns.call_encoding,
+            breaker_threshold=ns.breaker_threshold,
+            breaker_cooldown=ns.breaker_cooldown,
         )
 
     @classmethod
@@ -224,6 +224,130 @@ def parse_connection(config_files, args):
                         help="The client handshake timeout. Set to 0 to"
                         "disable.", default=0, type=int,
                         env_var="HELLO_TIMEOUT")
+    parser.add_argument('--metrics_backend',
+                        help="Metrics backend for the Rust connection node, "
+                        "statsd or prometheus (served at /metrics)",
+                        choices=["statsd", "prometheus"], default="statsd",
+                        type=str, env_var="METRICS_BACKEND")
+    parser.add_argument('--ssl_chain',
+                        help="Intermediate certificate chain for ssl_cert "
+                        "(Rust connection node)", type=str, default=None,
+                        env_var="SSL_CHAIN")
+    parser.add_argument('--ssl_sni_certs',
+                        help="JSON list of additional certificates served "
+                        "by SNI hostname, e.g. [{\"key\": ..., \"cert\": "
+                        "..., \"chain\": ...}] (Rust connection node)",
+                        type=str, default=None, env_var="SSL_SNI_CERTS")
+    parser.add_argument('--router_ssl_ca',
+                        help="CA file client certificates on the routing "
+                        "listener must be signed by (Rust connection node)",
+                        type=str, default=None, env_var="ROUTER_SSL_CA")
+    parser.add_argument('--router_client_certs',
+                        help="Allowed TLS client certificates on the routing "
+                        "listener, in the same format as client_certs (Rust "
+                        "connection node)",
+                        type=str, default=None, env_var="ROUTER_CLIENT_CERTS")
+    parser.add_argument('--ws_subprotocols',
+                        help="Comma separated list of websocket subprotocols "
+                        "clients may negotiate (Rust connection node)",
+                        type=str,
+                        default="push-notification,push-notification-msgpack",
+                        env_var="WS_SUBPROTOCOLS")
+    parser.add_argument('--allowed_origins',
+                        help="Comma separated list of Origins allowed to open "
+                        "a websocket, all if unset (Rust connection node)",
+                        type=str, default=None, env_var="ALLOWED_ORIGINS")
+    parser.add_argument('--ws_deflate',
+                        help="Offer permessage-deflate compression to "
+                        "websocket clients (Rust connection node)",
+                        action="store_true", default=False,
+                        env_var="WS_DEFLATE")
+    parser.add_argument('--ws_deflate_window_bits',
+                        help="Largest compression window in bits, 9 to 15 "
+                        "(Rust connection node)",
+                        type=int, default=15, env_var="WS_DEFLATE_WINDOW_BITS")
+    parser.add_argument('--ws_deflate_context_takeover',
+                        help="Keep compression contexts between messages "
+                        "(Rust connection node)",
+                        action="store_true", default=False,
+                        env_var="WS_DEFLATE_CONTEXT_TAKEOVER")
+    parser.add_argument('--ws_deflate_memory',
+                        help="Most bytes the compression contexts of a "
+                        "connection may take up, 0 for no limit (Rust "
+                        "connection node)",
+                        type=int, default=0, env_var="WS_DEFLATE_MEMORY")
+    parser.add_argument('--push_service_key',
+                        help="Bearer token required by the RFC 8030 POST "
+                        "/subscribe, which with the other RFC 8030 "
+                        "resources is off if unset (Rust connection node)",
+                        type=str, default=None, env_var="PUSH_SERVICE_KEY")
+    parser.add_argument('--max_message_size',
+                        help="Largest websocket message in bytes accepted "
+                        "from clients, 0 for no limit (Rust connection node)",
+                        type=int, default=262144, env_var="MAX_MESSAGE_SIZE")
+    parser.add_argument('--max_frame_size',
+                        help="Largest websocket frame in bytes accepted "
+                        "from clients, 0 for no limit (Rust connection node)",
+                        type=int, default=0, env_var="MAX_FRAME_SIZE")
+    parser.add_argument('--max_send_queue',
+                        help="Most messages queued for a client before "
+                        "waiting for them to be written, 0 for no limit "
+                        "(Rust connection node)",
+                        type=int, default=100, env_var="MAX_SEND_QUEUE")
+    parser.add_argument('--client_queue_depth',
+                        help="Notifications buffered for a client before "
+                        "further ones are stored (Rust connection node)",
+                        type=int, default=64, env_var="CLIENT_QUEUE_DEPTH")
+    parser.add_argument('--local_receipts',
+                        help="Record delivery receipts of messages sent "
+                        "without a Push-Receipt URL (Rust connection node)",
+                        action="store_true", default=False,
+                        env_var="LOCAL_RECEIPTS")
+    parser.add_argument('--spool_path',
+                        help="File undelivered notifications are spooled to "
+                        "while they can't be stored (Rust connection node)",
+                        type=str, default=None, env_var="SPOOL_PATH")
+    parser.add_argument('--spool_max_bytes',
+                        help="Most bytes the spool may take up, undelivered "
+                        "notifications that don't fit being dropped, 0 for "
+                        "no limit (Rust connection node)",
+                        type=int, default=0, env_var="SPOOL_MAX_BYTES")
+    parser.add_argument('--call_retries',
+                        help="JSON retry policies of failed storage calls by "
+                        "command or \"default\" (for idempotent commands "
+                        "only), e.g. {\"default\": "
+                        "{\"attempts\": 3, \"base_delay\": 0.1, "
+                        "\"max_delay\": 2}} (Rust connection node)",
+                        type=str,
+                        default='{"default": {"attempts": 3, '
+                        '"base_delay": 0.1, "max_delay": 2}}',
+                        env_var="CALL_RETRIES")
+    parser.add_argument('--call_timeouts',
+                        help="JSON seconds storage calls may take by command "
+                        "or \"default\", e.g. {\"default\": 30, "
+                        "\"check_storage\": 10} (Rust connection node)",
+                        type=str, default='{"default": 30}',
+                        env_var="CALL_TIMEOUTS")
+    parser.add_argument('--queue_capacity',
+                        help="Most storage calls waiting for a worker in "
+                        "each priority lane, new clients being turned away "
+                        "while interactive calls are at it, 0 for no limit "
+                        "(Rust connection node)",
+                        type=int, default=1000, env_var="QUEUE_CAPACITY")
+    parser.add_argument('--call_encoding',
+                        help="Encoding of storage calls, json being easier "
+                        "to debug (Rust connection node)",
+                        choices=["msgpack", "json"], default="msgpack",
+                        type=str, env_var="CALL_ENCODING")
+    parser.add_argument('--breaker_threshold',
+                        help="Storage calls failing in a row before new "
+                        "clients are turned away as overloaded, 0 to never "
+                        "turn them away (Rust connection node)",
+                        type=int, default=50, env_var="BREAKER_THRESHOLD")
+    parser.add_argument('--breaker_cooldown',
+                        help="Seconds new clients are turned away for once "
+                        "the breaker_threshold is hit (Rust connection node)",
+                        type=float, default=30, env_var="BREAKER_COOLDOWN")
 
     add_shared_args(parser)
     return parser.parse_args(args)
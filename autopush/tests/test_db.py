@@ -274,6 +274,36 @@ class MessageTestCase(unittest.TestCase):
             uuid.UUID(self.uaid), " ")
         assert len(all_messages) == 3
 
+    def test_message_storage_batched(self):
+        chid = str(uuid.uuid4())
+        m = get_rotating_message_table()
+        message = Message(m, SinkMetrics())
+        message.register_channel(self.uaid, chid)
+
+        message.store_messages([
+            make_webpush_notification(self.uaid, chid) for _ in range(3)])
+
+        _, all_messages = message.fetch_timestamp_messages(
+            uuid.UUID(self.uaid), " ")
+        assert len(all_messages) == 3
+
+    def test_message_storage_batched_topic(self):
+        """Test that a batch holding several messages with the same topic
+        keeps the last of them, rather than failing on the duplicate key"""
+        chid = str(uuid.uuid4())
+        notif1 = make_webpush_notification(self.uaid, chid)
+        notif2 = make_webpush_notification(self.uaid, chid)
+        notif1.topic = notif2.topic = "news"
+        m = get_rotating_message_table()
+        message = Message(m, SinkMetrics())
+        message.register_channel(self.uaid, chid)
+
+        message.store_messages([notif1, notif2])
+
+        _, messages = message.fetch_messages(uuid.UUID(self.uaid))
+        assert len(messages) == 1
+        assert messages[0].update_id == notif2.update_id
+
     def test_message_storage_overwrite(self):
         """Test that store_message can overwrite existing messages which
         can occur in some reconnect cases but shouldn't error"""
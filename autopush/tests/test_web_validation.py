@@ -574,6 +574,88 @@ class TestWebPushRequestSchema(unittest.TestCase):
         assert cm.value.message == ("Topic must be URL and Filename "
                                     "safe Base64 alphabet")
 
+    def test_push_receipt(self):
+        schema = self._make_fut()
+        schema.context["conf"].parse_endpoint.return_value = dict(
+            uaid=dummy_uaid,
+            chid=dummy_chid,
+            public_key="",
+        )
+        schema.context["db"].router.get_uaid.return_value = dict(
+            router_type="gcm",
+            uaid=dummy_uaid,
+            router_data=dict(creds=dict(senderID="bogus")),
+        )
+
+        info = self._make_test_data(
+            headers={
+                "push-receipt": "https://example.com/receipts/1",
+            }
+        )
+        result, errors = schema.load(info)
+        assert errors == {}
+        notif = result["notification"]
+        assert notif.receipt == "https://example.com/receipts/1"
+        assert notif.serialize()["receipt"] == notif.receipt
+
+        for receipt in ["ftp:example.com",
+                        "http://example.com/receipts/1",
+                        "https://localhost/receipts/1",
+                        "https://127.0.0.1/receipts/1",
+                        "https://10.0.0.1/receipts/1",
+                        "https://192.168.1.1:8443/receipts/1",
+                        "https://169.254.169.254/latest/meta-data",
+                        "https://[::1]/receipts/1",
+                        "https://[fe80::1]/receipts/1",
+                        "https://[::ffff:127.0.0.1]/receipts/1"]:
+            info = self._make_test_data(
+                headers={
+                    "push-receipt": receipt,
+                }
+            )
+
+            with pytest.raises(InvalidRequest) as cm:
+                schema.load(info)
+
+            assert cm.value.status_code == 400
+            assert cm.value.errno == 114
+
+    def test_urgency(self):
+        schema = self._make_fut()
+        schema.context["conf"].parse_endpoint.return_value = dict(
+            uaid=dummy_uaid,
+            chid=dummy_chid,
+            public_key="",
+        )
+        schema.context["db"].router.get_uaid.return_value = dict(
+            router_type="gcm",
+            uaid=dummy_uaid,
+            router_data=dict(creds=dict(senderID="bogus")),
+        )
+
+        info = self._make_test_data(
+            headers={
+                "urgency": "very-low",
+            }
+        )
+        result, errors = schema.load(info)
+        assert errors == {}
+        notif = result["notification"]
+        assert notif.urgency == "very-low"
+        assert notif.serialize()["urgency"] == "very-low"
+
+        info = self._make_test_data(
+            headers={
+                "urgency": "whenever",
+            }
+        )
+
+        with pytest.raises(InvalidRequest) as cm:
+            schema.load(info)
+
+        assert cm.value.status_code == 400
+        assert cm.value.errno == 115
+
     def test_no_current_month(self):
         schema = self._make_fut()
         schema.context["conf"].parse_endpoint.return_value = dict(
@@ -23,15 +23,20 @@ from autopush.tests.support import TestingLogObserver
 from autopush.utils import WebPushNotification, ns_time
 from autopush.websocket import USER_RECORD_VERSION
 from autopush.webpush_server import (
+    AckMessage,
     CheckStorage,
     DeleteMessage,
+    DeleteMessages,
     DropUser,
     Hello,
     HelloResponse,
     IncStoragePosition,
     MigrateUser,
+    MintSubscription,
     Register,
+    ResolveSubscription,
     StoreMessages,
+    StoreReceipt,
     Unregister,
     WebPushMessage,
 )
@@ -47,9 +52,12 @@ class AutopushCall(object):
         self.val = ret
         self.called.set()
 
-    def json(self):
+    def input(self):
         return self.payload
 
+    def cancelled(self):
+        return False
+
 
 class UserItemFactory(factory.Factory):
     class Meta:
@@ -174,7 +182,7 @@ class BaseSetup(unittest.TestCase):
         self.metrics = db.metrics = Mock(spec=SinkMetrics)
         db.setup_tables()
 
-    def _store_messages(self, uaid, topic=False, num=5):
+    def _store_messages(self, uaid, topic=False, num=5, receipt=None):
         try:
             item = self.db.router.get_uaid(uaid.hex)
             message_table = self.db.message_tables[item["current_month"]]
@@ -188,6 +196,7 @@ class BaseSetup(unittest.TestCase):
         for idx, notif in enumerate(messages):
             if topic:
                 notif.topic = "something_{}".format(idx)
+            notif.receipt = receipt
             notif.generate_message_id(self.conf.fernet)
             message_table.store_message(notif)
         return messages
@@ -221,6 +230,43 @@ class TestWebPushServer(BaseSetup):
         finally:
             ws.stop()
 
+    def test_reload_tls(self):
+        ws = self._makeFUT()
+        ws.start()
+        try:
+            assert ws.rust.reload_tls() is True
+            ws._reload_tls(None, None)
+            assert self.logs.logged(
+                lambda e: e['log_format'] == "Reloaded TLS certificates")
+        finally:
+            ws.stop()
+
+    def test_reload_tls_failed(self):
+        ws = self._makeFUT()
+        # Not running yet, so there's nothing to reload: that's reported
+        # rather than raised, and leaves the server usable
+        assert ws.rust.reload_tls() is False
+        ws._reload_tls(None, None)
+        assert self.logs.logged(
+            lambda e: e['log_format'].startswith(
+                "Failed to reload TLS certificates"))
+        ws.start()
+        try:
+            assert ws.rust.reload_tls() is True
+        finally:
+            ws.stop()
+
+    def test_stats(self):
+        ws = self._makeFUT()
+        assert ws.rust.stats() is None
+        ws.start()
+        try:
+            stats = ws.rust.stats()
+            assert stats["open_connections"] == 0
+            assert stats["registered_uaids"] == 0
+        finally:
+            ws.stop()
+
 
 class TestHelloProcessor(BaseSetup):
     def _makeFUT(self):
@@ -332,6 +378,21 @@ class TestCheckStorageProcessor(BaseSetup):
         result = p.process(check)
         assert len(result.messages) == 5
 
+    def test_receipt_kept(self):
+        p = self._makeFUT()
+        check = CheckStorageFactory(message_month=self.db.current_msg_month)
+        receipt = "https://example.com/receipts/1"
+        self._store_messages(check.uaid, topic=True, num=2, receipt=receipt)
+        self._store_messages(check.uaid, num=2, receipt=receipt)
+        result = p.process(check)
+        assert [m.receipt for m in result.messages] == [receipt] * 2
+
+        # Then the non-topic ones
+        check.timestamp = result.timestamp
+        check.include_topic = False
+        result = p.process(check)
+        assert [m.receipt for m in result.messages] == [receipt] * 2
+
 
 class TestIncrementStorageProcessor(BaseSetup):
     def _makeFUT(self):
@@ -397,6 +458,67 @@ class TestDeleteMessageProcessor(BaseSetup):
         assert len(results.messages) == 5
 
 
+class TestDeleteMessagesProcessor(BaseSetup):
+    def _makeFUT(self):
+        from autopush.webpush_server import DeleteMessagesCommand
+        return DeleteMessagesCommand(self.conf, self.db)
+
+    def test_delete_messages(self):
+        from autopush.webpush_server import CheckStorageCommand
+        check_command = CheckStorageCommand(self.conf, self.db)
+        check = CheckStorageFactory(message_month=self.db.current_msg_month)
+        delete_command = self._makeFUT()
+
+        # Store some topic messages
+        self._store_messages(check.uaid, topic=True, num=7)
+
+        # Fetch them
+        results = check_command.process(check)
+        assert len(results.messages) == 7
+
+        # Delete 3 of them in one go, as the Rust side sends them
+        result = delete_command.process(DeleteMessages(
+            message_month=self.db.current_msg_month,
+            messages=[attr.asdict(m) for m in results.messages[:3]],
+        ))
+        assert result.results == [True, True, True]
+
+        # Fetch messages again
+        results = check_command.process(check)
+        assert len(results.messages) == 4
+
+    def test_delete_messages_without_topic(self):
+        from autopush.webpush_server import CheckStorageCommand
+        check_command = CheckStorageCommand(self.conf, self.db)
+        check = CheckStorageFactory(message_month=self.db.current_msg_month)
+        delete_command = self._makeFUT()
+
+        # Store some timestamped messages, which are deleted in a batch
+        self._store_messages(check.uaid, num=5)
+
+        results = check_command.process(check)
+        assert len(results.messages) == 5
+
+        result = delete_command.process(DeleteMessages(
+            message_month=self.db.current_msg_month,
+            messages=[attr.asdict(m) for m in results.messages[:3]],
+        ))
+        assert result.results == [True, True, True]
+
+        results = check_command.process(check)
+        assert len(results.messages) == 2
+
+    def test_delete_messages_outcomes(self):
+        uaid = uuid4()
+        topics = self._store_messages(uaid, topic=True, num=2)
+        timestamped = self._store_messages(uaid, num=2)
+        # As though replaced by a newer message with the same topic since
+        topics[1].update_id = "stale"
+
+        results = self.db.message.delete_messages(topics + timestamped)
+        assert results == [True, False, True, True]
+
+
 class TestDropUserProcessor(BaseSetup):
     def _makeFUT(self):
         from autopush.webpush_server import DropUserCommand
@@ -561,3 +683,116 @@ class TestStoreMessagesProcessor(BaseSetup):
         store_message = StoreMessageFactory()
         response = cmd.process(store_message)
         assert response.success is True
+
+    def test_store_messages_receipt(self):
+        from autopush.webpush_server import CheckStorageCommand
+        cmd = self._makeFUT()
+        uaid = uuid4().hex
+        receipt = "https://example.com/receipts/1"
+        store_message = StoreMessageFactory(
+            uaid=uaid,
+            message_count=3,
+            message_month=self.db.current_msg_month,
+        )
+        for m in store_message.messages:
+            m["receipt"] = receipt
+        cmd.process(store_message)
+
+        check = CheckStorageFactory(
+            uaid=uaid, message_month=self.db.current_msg_month)
+        result = CheckStorageCommand(self.conf, self.db).process(check)
+        assert [m.receipt for m in result.messages] == [receipt] * 3
+
+
+class TestStoreReceiptProcessor(BaseSetup):
+    def _makeFUT(self):
+        from autopush.webpush_server import StoreReceiptCommand
+        return StoreReceiptCommand(self.conf, self.db)
+
+    def test_store_receipt(self):
+        cmd = self._makeFUT()
+        response = cmd.process(StoreReceipt(
+            uaid=uuid4().hex,
+            message_id="gAAAAABa",
+            channel_id=str(uuid4()),
+            delivered_at=int(time.time()),
+        ))
+        assert response.success is True
+
+
+class TestSubscriptionProcessors(BaseSetup):
+    def _register_user(self):
+        user = UserItemFactory(current_month=self.db.current_msg_month)
+        self.db.router.register_user(user)
+        return user["uaid"]
+
+    def _mint(self, uaid):
+        from autopush.webpush_server import MintSubscriptionCommand
+        command = MintSubscriptionCommand(self.conf, self.db)
+        return command.process(MintSubscription(uaid=uaid)).subscription
+
+    def _resolve(self, subscription):
+        from autopush.webpush_server import ResolveSubscriptionCommand
+        command = ResolveSubscriptionCommand(self.conf, self.db)
+        return command.process(ResolveSubscription(subscription=subscription))
+
+    def _ack(self, subscription, version):
+        from autopush.webpush_server import AckMessageCommand
+        command = AckMessageCommand(self.conf, self.db)
+        return command.process(AckMessage(
+            subscription=subscription,
+            version=version,
+        ))
+
+    def test_resolve_minted(self):
+        uaid = self._register_user()
+        subscription = self._mint(uaid)
+        assert uaid not in subscription
+
+        result = self._resolve(subscription)
+        assert result.uaid == uaid
+        assert result.message_month == self.db.current_msg_month
+
+    def test_resolve_forged(self):
+        uaid = self._register_user()
+        for subscription in (uaid, "s:" + uaid, "bogus", ""):
+            result = self._resolve(subscription)
+            assert result.uaid is None
+            assert result.message_month is None
+
+    def test_resolve_unknown_user(self):
+        result = self._resolve(self._mint(uuid4().hex))
+        assert result.uaid is None
+
+    def test_ack_message(self):
+        from autopush.webpush_server import CheckStorageCommand
+        uaid = self._register_user()
+        subscription = self._mint(uaid)
+        messages = self._store_messages(UUID(uaid), topic=True, num=3)
+
+        result = self._ack(subscription, messages[0].message_id)
+        assert result.deleted is True
+
+        check = CheckStorageFactory(
+            uaid=uaid, message_month=self.db.current_msg_month)
+        results = CheckStorageCommand(self.conf, self.db).process(check)
+        assert len(results.messages) == 2
+
+    def test_ack_other_users_message(self):
+        from autopush.webpush_server import CheckStorageCommand
+        uaid = self._register_user()
+        other = self._register_user()
+        messages = self._store_messages(UUID(uaid), topic=True, num=1)
+
+        result = self._ack(self._mint(other), messages[0].message_id)
+        assert result.deleted is False
+
+        check = CheckStorageFactory(
+            uaid=uaid, message_month=self.db.current_msg_month)
+        results = CheckStorageCommand(self.conf, self.db).process(check)
+        assert len(results.messages) == 1
+
+    def test_ack_invalid_version(self):
+        uaid = self._register_user()
+        result = self._ack(self._mint(uaid), "bogus")
+        assert result.deleted is False
@@ -34,6 +34,9 @@ from autopush.web.base import AUTH_SCHEMES
 # Crypto-Key and Encryption
 STRIP_PADDING = re.compile('=+(?=[,;]|$)')
 
+# RFC 8030 Urgency header values, from least to most urgent
+URGENCIES = ("very-low", "low", "normal", "high")
+
 
 # List of valid user-agent attributes to keep, anything not in this list is
 # considered 'Other'. We log the user-agent on connect always to retain the
@@ -291,6 +294,12 @@ class WebPushNotification(object):
     topic = attrib(default=None)  # type: Optional[str]
     source = attrib(default="Direct")  # type: Optional[str]
 
+    # RFC 8030 urgency, one of URGENCIES (None being "normal")
+    urgency = attrib(default=None)  # type: Optional[str]
+
+    # Where to send a delivery receipt once the client acks this, if anywhere
+    receipt = attrib(default=None)  # type: Optional[str]
+
     message_id = attrib(default=None)  # type: str
 
     # Not an alias for message_id, for backwards compat and cases where an old
@@ -485,6 +494,8 @@ class WebPushNotification(object):
             update_id=item.get("updateid"),
             timestamp=item.get("timestamp"),
             sortkey_timestamp=key_info.get("sortkey_timestamp"),
+            urgency=item.get("urgency"),
+            receipt=item.get("receipt"),
             source="Stored"
         )
 
@@ -509,6 +520,8 @@ class WebPushNotification(object):
             headers=data["headers"],
             ttl=data["headers"]["ttl"],
             topic=data["headers"]["topic"],
+            urgency=data["headers"].get("urgency"),
+            receipt=data["headers"].get("push_receipt"),
             legacy=legacy,
         )
 
@@ -560,6 +573,8 @@ class WebPushNotification(object):
                     message_id=str(data["version"]),
                     update_id=str(data["version"]),
                     timestamp=data.get("timestamp"),
+                    urgency=data.get("urgency"),
+                    receipt=data.get("receipt"),
                     )
         return notif
 
@@ -587,6 +602,10 @@ class WebPushNotification(object):
         if self.data:
             payload["data"] = self.data
             payload["headers"] = self.headers
+        if self.urgency:
+            payload["urgency"] = self.urgency
+        if self.receipt:
+            payload["receipt"] = self.receipt
         return payload
 
     def websocket_format(self):
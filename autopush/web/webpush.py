@@ -1,5 +1,8 @@
 import re
+import socket
+import struct
 import time
+from urlparse import urlparse
 
 from boto.dynamodb2.exceptions import ItemNotFound
 from cryptography.fernet import InvalidToken
@@ -42,6 +45,7 @@ from autopush.utils import (
     WebPushNotification,
     normalize_id,
     parse_auth_header,
+    URGENCIES,
 )
 from autopush.web.base import (
     threaded_validate,
@@ -163,10 +167,61 @@ class WebPushSubscriptionSchema(Schema):
                                  errno=106)
 
 
+# IPv4 networks receipts may not be sent to, as (address, prefix length):
+# "this" network, private, shared (carrier-grade NAT), loopback,
+# link-local, and multicast through broadcast
+_PRIVATE_IPV4 = [
+    (0x00000000, 8),
+    (0x0A000000, 8),
+    (0x64400000, 10),
+    (0x7F000000, 8),
+    (0xA9FE0000, 16),
+    (0xAC100000, 12),
+    (0xC0A80000, 16),
+    (0xE0000000, 3),
+]
+
+
+def _is_private_ipv4(addr):
+    # type: (int) -> bool
+    return any(addr >> (32 - bits) == net >> (32 - bits)
+               for net, bits in _PRIVATE_IPV4)
+
+
+def _is_private_host(host):
+    # type: (str) -> bool
+    """Whether a receipt URL's host is this machine or a non-public address
+
+    Only literal addresses can be checked here, names are resolved and
+    checked again by the connection node before a receipt is sent.
+
+    """
+    if host == "localhost" or host.endswith(".localhost"):
+        return True
+    try:
+        packed = socket.inet_pton(socket.AF_INET6, host)
+    except (socket.error, ValueError):
+        try:
+            packed = socket.inet_aton(host)
+        except (socket.error, ValueError):
+            return False
+        return _is_private_ipv4(struct.unpack("!I", packed)[0])
+    high, low = struct.unpack("!QQ", packed)
+    if high == 0 and low >> 32 in (0, 0xffff):
+        # IPv4-compatible and IPv4-mapped, including :: and ::1
+        return _is_private_ipv4(low & 0xffffffff)
+    # Unique local, link-local and multicast
+    return (high >> 57 == 0x7e or high >> 54 == 0x3fa or
+            high >> 56 == 0xff)
+
+
 class WebPushBasicHeaderSchema(Schema):
     authorization = fields.String()
     ttl = fields.Integer(required=False, missing=None)
     topic = fields.String(required=False, missing=None)
+    push_receipt = fields.String(required=False, missing=None,
+                                 load_from="push-receipt")
+    urgency = fields.String(required=False, missing=None)
     api_ver = fields.String()
 
     @validates('topic')
@@ -182,6 +237,28 @@ class WebPushBasicHeaderSchema(Schema):
             raise InvalidRequest("Topic must be URL and Filename safe Base"
                                  "64 alphabet", errno=113)
 
+    @validates('urgency')
+    def validate_urgency(self, value):
+        if value is None:
+            return True
+
+        if value not in URGENCIES:
+            raise InvalidRequest("Urgency must be one of: {}".format(
+                ", ".join(URGENCIES)), errno=115)
+
+    @validates('push_receipt')
+    def validate_push_receipt(self, value):
+        if value is None:
+            return True
+
+        url = urlparse(value)
+        if url.scheme != "https" or not url.hostname:
+            raise InvalidRequest("Push-Receipt must be an https URL",
+                                 errno=114)
+        if _is_private_host(url.hostname):
+            raise InvalidRequest("Push-Receipt must be a public URL",
+                                 errno=114)
+
     @post_load
     def cap_ttl(self, d):
         if 'ttl' in d:
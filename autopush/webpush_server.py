@@ -1,6 +1,7 @@
 """WebPush Server
 
 """
+import signal
 from threading import Thread
 from uuid import UUID, uuid4
 
@@ -11,6 +12,7 @@ from attr import (
 )
 from boto.dynamodb2.exceptions import ItemNotFound
 from botocore.exceptions import ClientError
+from cryptography.fernet import InvalidToken
 from typing import (  # noqa
     Dict,
     List,
@@ -28,6 +30,8 @@ from autopush.db import (  # noqa
 )
 
 from autopush.config import AutopushConfig  # noqa
+from autopush.exceptions import InvalidTokenException
+from autopush.jwt import repad
 from autopush.metrics import IMetrics  # noqa
 from autopush.web.webpush import MAX_TTL
 from autopush.types import JSONDict  # noqa
@@ -40,6 +44,14 @@ log = Logger()
 # sentinel objects
 _STOP = object()
 
+# Most calls a worker takes off the queue at once, and how long (in seconds)
+# it waits for one before checking whether it should still be running
+RECV_BATCH_SIZE = 10
+RECV_TIMEOUT = 1.0
+
+# Marks a decrypted token as naming a push service subscription resource
+SUBSCRIPTION_PREFIX = "s:"
+
 
 # Conversion functions
 def uaid_from_str(input):
@@ -67,6 +79,8 @@ class WebPushMessage(object):
     sortkey_timestamp = attrib(default=None)  # type: Optional[int]
     data = attrib(default=None)  # type: Optional[str]
     headers = attrib(default=None)  # type: Optional[JSONDict]
+    urgency = attrib(default=None)  # type: Optional[str]
+    receipt = attrib(default=None)  # type: Optional[str]
 
     @classmethod
     def from_WebPushNotification(cls, notif):
@@ -79,6 +93,8 @@ class WebPushMessage(object):
             sortkey_timestamp=notif.sortkey_timestamp,
             ttl=MAX_TTL if notif.ttl is None else int(notif.ttl),
             topic=notif.topic,
+            urgency=notif.urgency,
+            receipt=notif.receipt,
             **p
         )
 
@@ -91,7 +107,10 @@ class WebPushMessage(object):
             headers=self.headers,
             ttl=self.ttl,
             topic=self.topic,
+            urgency=self.urgency,
+            receipt=self.receipt,
             timestamp=self.timestamp,
+            sortkey_timestamp=self.sortkey_timestamp,
             message_id=self.version,
             update_id=self.version,
         )
@@ -132,6 +151,14 @@ class DeleteMessage(InputCommand):
     message = attrib()  # type: WebPushMessage
 
 
+@attrs(slots=True)
+class DeleteMessages(InputCommand):
+    message_month = attrib()  # type: str
+    messages = attrib(
+        default=attr.Factory(list)
+    )  # type: List[WebPushMessage]
+
+
 @attrs(slots=True)
 class DropUser(InputCommand):
     uaid = attrib(convert=uaid_from_str)  # type: UUID
@@ -188,6 +215,11 @@ class DeleteMessageResponse(OutputCommand):
     success = attrib(default=True)  # type: bool
 
 
+@attrs(slots=True)
+class DeleteMessagesResponse(OutputCommand):
+    results = attrib(default=attr.Factory(list))  # type: List[bool]
+
+
 @attrs(slots=True)
 class DropUserResponse(OutputCommand):
     success = attrib(default=True)  # type: bool
@@ -213,7 +245,7 @@ class WebPushServer(object):
         self.db = db
         self.db.setup_tables()
         self.num_threads = num_threads
-        self.incoming = AutopushQueue()
+        self.incoming = AutopushQueue(conf.queue_capacity)
         self.workers = []  # type: List[Thread]
         self.command_processor = CommandProcessor(conf, self.db)
         self.rust = AutopushServer(conf, self.incoming)
@@ -221,8 +253,12 @@ class WebPushServer(object):
 
     def run(self):
         self.start()
+        signal.signal(signal.SIGHUP, self._reload_tls)
         for worker in self.workers:
-            worker.join()
+            # Join with a timeout so the main thread still gets to run
+            # signal handlers
+            while worker.is_alive():
+                worker.join(1)
 
     def start(self):
         # type: (int) -> None
@@ -240,28 +276,45 @@ class WebPushServer(object):
         self.running = False
         self.rust.stopService()
 
+    def _reload_tls(self, signum, frame):
+        """Pick up renewed TLS certificates without dropping connections"""
+        if self.rust.reload_tls():
+            log.info("Reloaded TLS certificates")
+        else:
+            log.error("Failed to reload TLS certificates, keeping the "
+                      "previous ones")
+
     def _create_thread_worker(self, processor, input_queue):
         # type: (CommandProcessor, AutopushQueue) -> Thread
         def _thread_worker():
             while self.running:
-                call = input_queue.recv()
-                try:
-                    if call is None:
-                        break
-                    command = call.json()
-                    result = processor.process_message(command)
-                    call.complete(result)
-                except Exception as exc:
-                    # TODO: Handle traceback better
-                    import traceback
-                    traceback.print_exc()
-                    log.error("Exception in worker queue thread")
-                    call.complete(dict(
-                        error=True,
-                        error_msg=str(exc),
-                    ))
+                calls = input_queue.recv_batch(RECV_BATCH_SIZE, RECV_TIMEOUT)
+                if not calls and input_queue.closed():
+                    break
+                for call in calls:
+                    self._process_call(processor, call)
         return self.spawn(_thread_worker)
 
+    def _process_call(self, processor, call):
+        # type: (CommandProcessor, AutopushCall) -> None
+        try:
+            if call.cancelled():
+                # Already past its deadline, nobody wants the result
+                call.cancel()
+                return
+            command = call.input()
+            result = processor.process_message(command)
+            call.complete(result)
+        except Exception as exc:
+            # TODO: Handle traceback better
+            import traceback
+            traceback.print_exc()
+            log.error("Exception in worker queue thread")
+            call.complete(dict(
+                error=True,
+                error_msg=str(exc),
+            ))
+
     def spawn(self, func, *args, **kwargs):
         t = Thread(target=func, args=args, kwargs=kwargs)
         t.start()
@@ -277,32 +330,48 @@ class CommandProcessor(object):
         self.check_storage_processor = CheckStorageCommand(conf, db)
         self.inc_storage_processor = IncrementStorageCommand(conf, db)
         self.delete_message_processor = DeleteMessageCommand(conf, db)
+        self.delete_messages_processor = DeleteMessagesCommand(conf, db)
         self.drop_user_processor = DropUserCommand(conf, db)
         self.migrate_user_proocessor = MigrateUserCommand(conf, db)
         self.register_process = RegisterCommand(conf, db)
         self.unregister_process = UnregisterCommand(conf, db)
         self.store_messages_process = StoreMessagesUserCommand(conf, db)
+        self.store_receipt_process = StoreReceiptCommand(conf, db)
+        self.mint_subscription_process = MintSubscriptionCommand(conf, db)
+        self.resolve_subscription_process = ResolveSubscriptionCommand(
+            conf, db)
+        self.ack_message_process = AckMessageCommand(conf, db)
         self.deserialize = dict(
             hello=Hello,
             check_storage=CheckStorage,
             inc_storage_position=IncStoragePosition,
             delete_message=DeleteMessage,
+            delete_messages=DeleteMessages,
             drop_user=DropUser,
             migrate_user=MigrateUser,
             register=Register,
             unregister=Unregister,
             store_messages=StoreMessages,
+            store_receipt=StoreReceipt,
+            mint_subscription=MintSubscription,
+            resolve_subscription=ResolveSubscription,
+            ack_message=AckMessage,
         )
         self.command_dict = dict(
             hello=self.hello_processor,
             check_storage=self.check_storage_processor,
             inc_storage_position=self.inc_storage_processor,
             delete_message=self.delete_message_processor,
+            delete_messages=self.delete_messages_processor,
             drop_user=self.drop_user_processor,
             migrate_user=self.migrate_user_proocessor,
             register=self.register_process,
             unregister=self.unregister_process,
             store_messages=self.store_messages_process,
+            store_receipt=self.store_receipt_process,
+            mint_subscription=self.mint_subscription_process,
+            resolve_subscription=self.resolve_subscription_process,
+            ack_message=self.ack_message_process,
         )  # type: Dict[str, ProcessorCommand]
 
     def process_message(self, input):
@@ -502,6 +571,18 @@ class DeleteMessageCommand(ProcessorCommand):
         return DeleteMessageResponse()
 
 
+class DeleteMessagesCommand(ProcessorCommand):
+    def process(self, command):
+        # type: (DeleteMessages) -> DeleteMessagesResponse
+        message = self.db.message_tables[command.message_month]
+        notifs = []
+        for m in command.messages:
+            if "topic" not in m:
+                m["topic"] = None
+            notifs.append(WebPushMessage(**m).to_WebPushNotification())
+        return DeleteMessagesResponse(results=message.delete_messages(notifs))
+
+
 class DropUserCommand(ProcessorCommand):
     def process(self, command):
         # type: (DropUser) -> DropUserResponse
@@ -532,11 +613,12 @@ class StoreMessagesUserCommand(ProcessorCommand):
     def process(self, command):
         # type: (StoreMessages) -> StoreMessagesResponse
         message = self.db.message_tables[command.message_month]
+        notifs = []
         for m in command.messages:
             if "topic" not in m:
                 m["topic"] = None
-            notif = WebPushMessage(**m).to_WebPushNotification()
-            message.store_message(notif)
+            notifs.append(WebPushMessage(**m).to_WebPushNotification())
+        message.store_messages(notifs)
         return StoreMessagesResponse()
 
 
@@ -650,3 +732,129 @@ class UnregisterCommand(ProcessorCommand):
             **dict(code=command.code) if command.code else {}
         )
         return UnregisterResponse()
+
+
+@attrs(slots=True)
+class StoreReceipt(InputCommand):
+    uaid = attrib(convert=uaid_from_str)  # type: UUID
+    message_id = attrib()  # type: str
+    channel_id = attrib()  # type: str
+    delivered_at = attrib()  # type: int
+
+
+@attrs(slots=True)
+class StoreReceiptResponse(OutputCommand):
+    success = attrib(default=True)  # type: bool
+
+
+class StoreReceiptCommand(ProcessorCommand):
+    def process(self, command):
+        # type: (StoreReceipt) -> StoreReceiptResponse
+        """Record the delivery of a message sent without a receipt URL"""
+        self.metrics.increment('ua.command.store_receipt')
+        log.info(
+            "Delivery receipt",
+            channel_id=command.channel_id,
+            message_id=command.message_id,
+            delivered_at=command.delivered_at,
+            uaid_hash=hasher(command.uaid.hex),
+        )
+        return StoreReceiptResponse()
+
+
+@attrs(slots=True)
+class MintSubscription(InputCommand):
+    uaid = attrib(convert=uaid_from_str)  # type: UUID
+
+
+@attrs(slots=True)
+class MintSubscriptionResponse(OutputCommand):
+    subscription = attrib()  # type: str
+
+
+@attrs(slots=True)
+class ResolveSubscription(InputCommand):
+    subscription = attrib()  # type: str
+
+
+@attrs(slots=True)
+class ResolveSubscriptionResponse(OutputCommand):
+    uaid = attrib(default=None)  # type: Optional[str]
+    message_month = attrib(default=None)  # type: Optional[str]
+
+
+@attrs(slots=True)
+class AckMessage(InputCommand):
+    subscription = attrib()  # type: str
+    version = attrib()  # type: str
+
+
+@attrs(slots=True)
+class AckMessageResponse(OutputCommand):
+    deleted = attrib(default=False)  # type: bool
+
+
+class SubscriptionCommand(ProcessorCommand):
+    """Base for the commands behind the RFC 8030 subscription resources
+
+    A subscription resource is named by a token encrypting the uaid, so it
+    can't be guessed or forged. The message table is always looked up from
+    the user record, never taken from the request.
+
+    """
+    def resolve(self, subscription):
+        # type: (str) -> Tuple[Optional[str], Optional[str]]
+        """The uaid and current message table of a subscription token, or
+        Nones if it's invalid or the user is gone"""
+        try:
+            token = self.conf.fernet.decrypt(
+                repad(str(subscription)).encode('utf8'))
+        except (InvalidToken, TypeError, UnicodeError):
+            return None, None
+        if not token.startswith(SUBSCRIPTION_PREFIX):
+            return None, None
+        uaid = token[len(SUBSCRIPTION_PREFIX):]
+        try:
+            record = self.db.router.get_uaid(uaid)
+        except ItemNotFound:
+            return None, None
+        month = record.get("current_month")
+        if month not in self.db.message_tables:
+            return None, None
+        return uaid, month
+
+
+class MintSubscriptionCommand(SubscriptionCommand):
+    def process(self, command):
+        # type: (MintSubscription) -> MintSubscriptionResponse
+        token = self.conf.fernet.encrypt(
+            SUBSCRIPTION_PREFIX + command.uaid.hex)
+        return MintSubscriptionResponse(subscription=token.strip('='))
+
+
+class ResolveSubscriptionCommand(SubscriptionCommand):
+    def process(self, command):
+        # type: (ResolveSubscription) -> ResolveSubscriptionResponse
+        uaid, month = self.resolve(command.subscription)
+        return ResolveSubscriptionResponse(uaid=uaid, message_month=month)
+
+
+class AckMessageCommand(SubscriptionCommand):
+    def process(self, command):
+        # type: (AckMessage) -> AckMessageResponse
+        """Delete a stored message by its version, as long as it belongs to
+        the subscription's user"""
+        uaid, month = self.resolve(command.subscription)
+        if uaid is None:
+            return AckMessageResponse(deleted=False)
+        try:
+            notif = WebPushNotification.from_message_id(
+                bytes(command.version),
+                fernet=self.conf.fernet,
+            )
+        except (InvalidToken, InvalidTokenException):
+            return AckMessageResponse(deleted=False)
+        if notif.uaid.hex != uaid:
+            return AckMessageResponse(deleted=False)
+        message = self.db.message_tables[month]
+        return AckMessageResponse(deleted=message.delete_message(notif))
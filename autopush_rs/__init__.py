@@ -1,7 +1,19 @@
 import json
+import threading
+
+try:
+    import msgpack
+except ImportError:  # pragma: nocover
+    msgpack = None
 
 from autopush_rs._native import ffi, lib
 
+# Outcomes of AutopushServer.notify and check_storage
+NOTIFY_DELIVERED = 0
+NOTIFY_NOT_CONNECTED = 1
+NOTIFY_BACKPRESSURE = 2
+NOTIFY_INVALID = 3
+
 
 def ffi_from_buffer(s):
     if s is None:
@@ -19,6 +31,12 @@ def free(obj, free_fn):
 
 
 class AutopushServer(object):
+    """The Rust connection node, running on its own thread.
+
+    Safe to use from several Python threads at once: notify, check_storage,
+    reload_tls and stats may all be called concurrently.
+
+    """
     def __init__(self, conf, queue):
         # type: (AutopushConfig, AutopushQueue) -> AutopushServer
         cfg = ffi.new('AutopushServerOptions*')
@@ -34,10 +52,40 @@ class AutopushServer(object):
         cfg.ssl_cert = ffi_from_buffer(conf.ssl.cert)
         cfg.ssl_dh_param = ffi_from_buffer(conf.ssl.dh_param)
         cfg.ssl_key = ffi_from_buffer(conf.ssl.key)
+        cfg.ssl_chain = ffi_from_buffer(conf.ssl_chain)
+        cfg.ssl_sni_certs = ffi_from_buffer(conf.ssl_sni_certs)
+        cfg.router_ssl_key = ffi_from_buffer(conf.router_ssl.key)
+        cfg.router_ssl_cert = ffi_from_buffer(conf.router_ssl.cert)
+        cfg.router_ssl_ca = ffi_from_buffer(conf.router_ssl_ca)
+        cfg.router_client_certs = ffi_from_buffer(conf.router_client_certs)
+        cfg.ws_subprotocols = ffi_from_buffer(conf.ws_subprotocols)
+        cfg.allowed_origins = ffi_from_buffer(conf.allowed_origins)
+        cfg.ws_deflate = conf.ws_deflate
+        cfg.ws_deflate_window_bits = conf.ws_deflate_window_bits
+        cfg.ws_deflate_context_takeover = conf.ws_deflate_context_takeover
+        cfg.ws_deflate_memory = conf.ws_deflate_memory
+        cfg.push_service_key = ffi_from_buffer(conf.push_service_key)
+        cfg.max_message_size = conf.max_message_size
+        cfg.max_frame_size = conf.max_frame_size
+        cfg.max_send_queue = conf.max_send_queue
+        cfg.client_queue_depth = conf.client_queue_depth
+        cfg.local_receipts = conf.local_receipts
+        cfg.spool_path = ffi_from_buffer(conf.spool_path)
+        cfg.spool_max_bytes = conf.spool_max_bytes
+        cfg.call_retries = ffi_from_buffer(conf.call_retries)
+        cfg.call_timeouts = ffi_from_buffer(conf.call_timeouts)
+        # Fall back to JSON if msgpack isn't around to decode calls with
+        call_encoding = conf.call_encoding
+        if msgpack is None:
+            call_encoding = "json"
+        cfg.call_encoding = ffi_from_buffer(call_encoding)
+        cfg.breaker_threshold = conf.breaker_threshold
+        cfg.breaker_cooldown = conf.breaker_cooldown
         cfg.url = ffi_from_buffer(conf.ws_url)
         cfg.json_logging = True
         cfg.statsd_host = ffi_from_buffer(conf.statsd_host)
         cfg.statsd_port = conf.statsd_port
+        cfg.metrics_backend = ffi_from_buffer(conf.metrics_backend)
 
         ptr = _call(lib.autopush_server_new, cfg)
         self.ffi = ffi.gc(ptr, lib.autopush_server_free)
@@ -54,6 +102,56 @@ class AutopushServer(object):
         _call(lib.autopush_server_stop, self.ffi)
         self._free_ffi()
 
+    def reload_tls(self):
+        """Swap in the TLS key and certificates currently on disk.
+
+        Returns whether they were, the previous ones staying in use if they
+        fail to load.
+
+        """
+        return _call(lib.autopush_server_reload_tls, self.ffi) - 1 == 1
+
+    def notify(self, uaid, notif):
+        """Hand the notification dict notif to the client connected here with
+        uaid.
+
+        Returns NOTIFY_DELIVERED if it took it, NOTIFY_NOT_CONNECTED if no
+        such client is connected here, NOTIFY_BACKPRESSURE if it has too
+        many notifications waiting already and this one should be stored, or
+        NOTIFY_INVALID if uaid or notif couldn't be parsed.
+
+        """
+        return _call(lib.autopush_server_notify, self.ffi, str(uaid),
+                     json.dumps(notif)) - 1
+
+    def check_storage(self, uaid):
+        """Let the client connected here with uaid know it has notifications
+        stored.
+
+        Returns NOTIFY_DELIVERED, NOTIFY_NOT_CONNECTED if no such client is
+        connected here, or NOTIFY_INVALID if uaid couldn't be parsed.
+
+        """
+        return _call(lib.autopush_server_check_storage, self.ffi,
+                     str(uaid)) - 1
+
+    def stats(self):
+        """A snapshot of what the server's up to, as a dict.
+
+        Includes the open connections (and how many of those are TLS or
+        plaintext), registered UAIDs, clients by state, calls waiting for
+        Python by priority, and bytes read from and written to clients.
+        None if the server isn't running.
+
+        """
+        ptr = _call(lib.autopush_server_stats, self.ffi)
+        if ffi.cast('size_t', ptr) == 1:
+            return None
+        stats = ffi.gc(ptr, lib.autopush_server_stats_free)
+        json_ptr = _call(lib.autopush_server_stats_ptr, stats)
+        json_len = _call(lib.autopush_server_stats_len, stats) - 1
+        return json.loads(ffi.buffer(json_ptr, json_len)[:])
+
     def _free_ffi(self):
         free(self, lib.autopush_server_free)
 
@@ -62,61 +160,110 @@ class AutopushCall:
     def __init__(self, ptr):
         self.ffi = ffi.gc(ptr, lib.autopush_python_call_free)
 
-    def json(self):
+    def input(self):
+        """The decoded arguments of this call"""
         msg_ptr = _call(lib.autopush_python_call_input_ptr, self.ffi)
         msg_len = _call(lib.autopush_python_call_input_len, self.ffi) - 1
         buf = ffi.buffer(msg_ptr, msg_len)
+        if self._msgpack():
+            return msgpack.unpackb(buf[:], encoding='utf-8')
         return json.loads(str(buf[:]))
 
     def complete(self, ret):
-        s = json.dumps(ret)
-        _call(lib.autopush_python_call_complete, self.ffi, s)
+        if self._msgpack():
+            s = msgpack.packb(ret, use_bin_type=False)
+            _call(lib.autopush_python_call_complete_bytes, self.ffi, s,
+                  len(s))
+        else:
+            s = json.dumps(ret)
+            _call(lib.autopush_python_call_complete, self.ffi, s)
         self._free_ffi()
 
+    def _msgpack(self):
+        return _call(lib.autopush_python_call_msgpack, self.ffi) - 1 == 1
+
     def cancel(self):
         self._free_ffi()
 
+    def cancelled(self):
+        """Whether Rust gave up waiting on this call"""
+        return _call(lib.autopush_python_call_cancelled, self.ffi) - 1 == 1
+
     def _free_ffi(self):
         free(self, lib.autopush_python_call_free)
 
 
 class AutopushQueue:
-    def __init__(self):
-        ptr = _call(lib.autopush_queue_new)
+    def __init__(self, capacity=0):
+        ptr = _call(lib.autopush_queue_new, capacity)
         self.ffi = ffi.gc(ptr, lib.autopush_queue_free)
 
     def recv(self):
         if self.ffi is None:
             return None
         ret = _call(lib.autopush_queue_recv, self.ffi)
+        return self._wrap(ret)
+
+    def try_recv(self):
+        """The next call if one is queued up, otherwise None"""
+        if self.ffi is None:
+            return None
+        ret = _call(lib.autopush_queue_try_recv, self.ffi)
+        return self._wrap(ret)
+
+    def recv_timeout(self, timeout):
+        """The next call, waiting at most timeout seconds for one"""
+        if self.ffi is None:
+            return None
+        ret = _call(lib.autopush_queue_recv_timeout, self.ffi,
+                    int(timeout * 1000))
+        return self._wrap(ret)
+
+    def recv_batch(self, max_calls, timeout):
+        """Up to max_calls calls, waiting at most timeout seconds for the
+        first one"""
+        if self.ffi is None:
+            return []
+        calls = ffi.new('AutopushPythonCall*[]', max_calls)
+        n = _call(lib.autopush_queue_recv_batch, self.ffi, calls, max_calls,
+                  int(timeout * 1000)) - 1
+        return [AutopushCall(calls[i]) for i in range(n)]
+
+    def closed(self):
+        """Whether the server is done sending calls"""
+        if self.ffi is None:
+            return True
+        return _call(lib.autopush_queue_closed, self.ffi) - 1 == 1
+
+    def _wrap(self, ret):
         if ffi.cast('size_t', ret) == 1:
             return None
         else:
             return AutopushCall(ret)
 
 
-last_err = None
+_errors = threading.local()
 
 
 def _call(f, *args):
     # We cache errors across invocations of `_call` to avoid allocating a new
     # error each time we call an FFI function. Each function call, however,
-    # needs a unique error, so take the global `last_err`, lazily initializing
-    # it if necessary.
-    global last_err
-    my_err = last_err
-    last_err = None
+    # needs a unique error, so take this thread's `last_err`, lazily
+    # initializing it if necessary. Calls may be made from several threads at
+    # once, which mustn't share an error.
+    my_err = getattr(_errors, 'last_err', None)
+    _errors.last_err = None
     if my_err is None:
         my_err = ffi.new('AutopushError*')
 
     # The error pointer is always the last argument, so pass that in and call
     # the actual FFI function. If the return value is nonzero then it was a
-    # successful call and we can put our error back into the global slot
+    # successful call and we can put our error back into this thread's slot
     # and return.
     args = args + (my_err,)
     ret = f(*args)
     if ffi.cast('size_t', ret) != 0:
-        last_err = my_err
+        _errors.last_err = my_err
         return ret
 
     # If an error happened then it means that the Rust side of things panicked
@@ -125,7 +272,7 @@ def _call(f, *args):
     #
     # Note that we're also careful here to clean up the error's internals to
     # avoid memory leaks and then once we're completely done we can restore our
-    # local error to its global position.
+    # local error to this thread's slot.
     errln = lib.autopush_error_msg_len(my_err);
     if errln > 0:
         ptr = lib.autopush_error_msg_ptr(my_err)
@@ -134,5 +281,5 @@ def _call(f, *args):
     else:
         exn = RuntimeError('unknown error in rust')
     lib.autopush_error_cleanup(my_err)
-    last_err = my_err
+    _errors.last_err = my_err
     raise exn
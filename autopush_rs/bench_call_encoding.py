@@ -0,0 +1,96 @@
+"""Benchmark of the encodings calls into Python can use
+
+Times the Python half of a call for the json and msgpack encodings: decoding
+the call's arguments and encoding its result. Batches of stored messages, as
+returned by check_storage and sent along with store_messages, make up the
+bulk of what crosses over, so that's what's measured.
+
+Run with::
+
+    python -m autopush_rs.bench_call_encoding [--messages 10,50,100]
+
+"""
+import argparse
+import base64
+import json
+import os
+import time
+import timeit
+from uuid import uuid4
+
+import attr
+import msgpack
+
+from autopush.webpush_server import CheckStorageResponse, WebPushMessage
+
+
+def make_messages(count, data_size=2048):
+    """Stored messages like the ones a returning client gets, with encrypted
+    payloads of data_size bytes"""
+    uaid = uuid4().hex
+    now = int(time.time())
+    return [
+        WebPushMessage(
+            uaid=uaid,
+            timestamp=now,
+            channelID=str(uuid4()),
+            ttl=86400,
+            topic=None,
+            version=uuid4().hex,
+            sortkey_timestamp=now,
+            data=base64.urlsafe_b64encode(os.urandom(data_size)),
+            headers={
+                "encoding": "aes128gcm",
+                "crypto_key": "dh=" + base64.urlsafe_b64encode(
+                    os.urandom(65)),
+            },
+        ) for _ in range(count)
+    ]
+
+
+ENCODINGS = dict(
+    json=(json.dumps, json.loads),
+    msgpack=(
+        lambda v: msgpack.packb(v, use_bin_type=False),
+        lambda s: msgpack.unpackb(s, encoding='utf-8'),
+    ),
+)
+
+
+def bench(count, number):
+    messages = make_messages(count)
+    call = dict(
+        command="store_messages",
+        message_month="message_2017_12",
+        messages=[attr.asdict(m) for m in messages],
+    )
+    result = attr.asdict(CheckStorageResponse(
+        include_topic=False,
+        messages=messages,
+        timestamp=None,
+    ))
+    for name, (encode, decode) in sorted(ENCODINGS.items()):
+        encoded_call = encode(call)
+        decode_time = timeit.timeit(
+            lambda: decode(encoded_call), number=number)
+        encode_time = timeit.timeit(lambda: encode(result), number=number)
+        print("{:>4} messages {:>8}: {:>8} bytes, decode {:7.1f}us, "
+              "encode {:7.1f}us".format(
+                  count, name, len(encoded_call),
+                  decode_time / number * 1e6,
+                  encode_time / number * 1e6))
+
+
+def main():
+    parser = argparse.ArgumentParser(description=__doc__.splitlines()[0])
+    parser.add_argument("--messages", default="1,10,50,100",
+                        help="Comma separated batch sizes to time")
+    parser.add_argument("--number", type=int, default=1000,
+                        help="Times each encode and decode is repeated")
+    args = parser.parse_args()
+    for count in args.messages.split(","):
+        bench(int(count), args.number)
+
+
+if __name__ == "__main__":
+    main()
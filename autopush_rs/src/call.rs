@@ -10,23 +10,52 @@
 //! Implementation-wise what's happening here is that each function call into
 //! Python creates a `futures::sync::oneshot`. The `Sender` half of this oneshot
 //! is sent to Python while the `Receiver` half stays in Rust. Arguments sent to
-//! Python are serialized as JSON and arguments are received from Python as JSON
-//! as well, meaning that they're deserialized in Rust from JSON as well.
+//! Python are serialized, and results received back deserialized, in the
+//! server's `CallEncoding`: MessagePack, or JSON when debugging.
+//!
+//! Every call is timed from `send_to_python` until its completion, along with
+//! how long it sat in the queue before a Python worker picked it up. Both are
+//! reported through `Server.metrics`, tagged by the call's `command`.
+//!
+//! Calls failing with a Python exception are retried according to the
+//! `RetryPolicy` configured for their `command`. Only idempotent commands
+//! fall back on the `"default"` policy, others being retried just when a
+//! policy names them. Calls succeeding or failing with a Python exception
+//! after retries feed the server's `CircuitBreaker`, which turns away new
+//! clients while the backend keeps failing. Timeouts and a full queue are
+//! our own load rather than the backend failing, and aren't counted.
+//!
+//! Each attempt may also be given a deadline, again by `command`. A call
+//! Python hasn't completed by then fails with `PythonTimeout` and is marked
+//! cancelled, so a late completion from Python is dropped rather than
+//! delivered.
 
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::cmp;
 use std::ffi::CStr;
+use std::slice;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
+use cadence::prelude::*;
+use cadence::StatsdClient;
 use futures::Future;
+use futures::future::{self, Either, Loop};
 use futures::sync::oneshot;
 use libc::c_char;
+use rand;
+use rmp_serde;
 use serde::de;
 use serde::ser;
 use serde_json;
+use tokio_core::reactor::{Handle, Timeout};
 use uuid::Uuid;
 
 use errors::*;
 use rt::{self, UnwindGuard, AutopushError};
 use protocol;
+use receipts::Receipt;
 use server::Server;
 
 #[repr(C)]
@@ -35,15 +64,61 @@ pub struct AutopushPythonCall {
 }
 
 struct Inner {
-    input: String,
+    input: Vec<u8>,
+    encoding: CallEncoding,
+    queued: Duration,
+    cancelled: Arc<AtomicBool>,
     done: RefCell<Option<Box<FnBox>>>,
 }
 
 pub struct PythonCall {
-    input: String,
+    input: Vec<u8>,
+    encoding: CallEncoding,
+    priority: Priority,
+    queued_at: Instant,
+    // Set once Rust gave up waiting on the call
+    cancelled: Arc<AtomicBool>,
     output: Box<FnBox>,
 }
 
+// What a `PythonCall` resolves to once Python is done with it
+struct Completion {
+    output: Result<Vec<u8>>,
+    queued: Duration,
+}
+
+/// How calls and their results are encoded on their way to and from Python
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum CallEncoding {
+    /// Easy to read, for debugging
+    Json,
+    /// Compact and much quicker to encode and decode
+    MessagePack,
+}
+
+impl CallEncoding {
+    fn encode<T: ser::Serialize>(&self, value: &T) -> Result<Vec<u8>> {
+        match *self {
+            CallEncoding::Json => Ok(serde_json::to_vec(value)?),
+            CallEncoding::MessagePack => {
+                rmp_serde::to_vec_named(value).chain_err(|| "failed to encode msgpack")
+            }
+        }
+    }
+
+    fn decode<T>(&self, bytes: &[u8]) -> Result<T>
+    where
+        T: for<'de> de::Deserialize<'de>,
+    {
+        match *self {
+            CallEncoding::Json => Ok(serde_json::from_slice(bytes)?),
+            CallEncoding::MessagePack => {
+                rmp_serde::from_slice(bytes).chain_err(|| "failed to decode msgpack")
+            }
+        }
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn autopush_python_call_input_ptr(
     call: *mut AutopushPythonCall,
@@ -60,6 +135,20 @@ pub extern "C" fn autopush_python_call_input_len(
     unsafe { (*call).inner.catch(err, |call| call.input.len()) }
 }
 
+/// Whether this call's input is MessagePack, and its result must be as well,
+/// rather than JSON.
+#[no_mangle]
+pub extern "C" fn autopush_python_call_msgpack(
+    call: *mut AutopushPythonCall,
+    err: &mut AutopushError,
+) -> usize {
+    unsafe {
+        (*call).inner.catch(err, |call| {
+            (call.encoding == CallEncoding::MessagePack) as usize
+        })
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn autopush_python_call_complete(
     call: *mut AutopushPythonCall,
@@ -68,12 +157,37 @@ pub extern "C" fn autopush_python_call_complete(
 ) -> i32 {
     unsafe {
         (*call).inner.catch(err, |call| {
-            let input = CStr::from_ptr(input).to_str().unwrap();
-            call.done.borrow_mut().take().unwrap().call(input);
+            call.complete(CStr::from_ptr(input).to_bytes());
+        })
+    }
+}
+
+/// Like `autopush_python_call_complete`, for results that may contain nul
+/// bytes such as MessagePack.
+#[no_mangle]
+pub extern "C" fn autopush_python_call_complete_bytes(
+    call: *mut AutopushPythonCall,
+    input: *const u8,
+    len: usize,
+    err: &mut AutopushError,
+) -> i32 {
+    unsafe {
+        (*call).inner.catch(err, |call| {
+            call.complete(slice::from_raw_parts(input, len));
         })
     }
 }
 
+/// Whether Rust already gave up on this call, in which case Python needn't
+/// bother processing it.
+#[no_mangle]
+pub extern "C" fn autopush_python_call_cancelled(
+    call: *mut AutopushPythonCall,
+    err: &mut AutopushError,
+) -> usize {
+    unsafe { (*call).inner.catch(err, |call| call.cancelled.load(Ordering::SeqCst) as usize) }
+}
+
 #[no_mangle]
 pub extern "C" fn autopush_python_call_free(call: *mut AutopushPythonCall) {
     rt::abort_on_panic(|| unsafe {
@@ -82,35 +196,53 @@ pub extern "C" fn autopush_python_call_free(call: *mut AutopushPythonCall) {
 }
 
 impl AutopushPythonCall {
+    /// Wraps up a `PythonCall` as it's handed to a Python worker, noting how
+    /// long it waited in the queue.
     pub fn new(call: PythonCall) -> AutopushPythonCall {
         AutopushPythonCall {
             inner: UnwindGuard::new(Inner {
                 input: call.input,
+                encoding: call.encoding,
+                queued: call.queued_at.elapsed(),
+                cancelled: call.cancelled,
                 done: RefCell::new(Some(call.output)),
             }),
         }
     }
 
-    fn _new<F>(input: String, f: F) -> AutopushPythonCall
+    fn _new<F>(input: Vec<u8>, f: F) -> AutopushPythonCall
     where
-        F: FnOnce(&str) + Send + 'static,
+        F: FnOnce(&[u8], Duration) + Send + 'static,
     {
         AutopushPythonCall {
             inner: UnwindGuard::new(Inner {
                 input: input,
+                encoding: CallEncoding::Json,
+                queued: Duration::new(0, 0),
+                cancelled: Arc::new(AtomicBool::new(false)),
                 done: RefCell::new(Some(Box::new(f))),
             }),
         }
     }
 }
 
+impl Inner {
+    fn complete(&self, output: &[u8]) {
+        let done = self.done.borrow_mut().take().unwrap();
+        // Nobody's waiting on the result of a call past its deadline
+        if !self.cancelled.load(Ordering::SeqCst) {
+            done.call(output, self.queued);
+        }
+    }
+}
+
 trait FnBox: Send {
-    fn call(self: Box<Self>, input: &str);
+    fn call(self: Box<Self>, input: &[u8], queued: Duration);
 }
 
-impl<F: FnOnce(&str) + Send> FnBox for F {
-    fn call(self: Box<Self>, input: &str) {
-        (*self)(input)
+impl<F: FnOnce(&[u8], Duration) + Send> FnBox for F {
+    fn call(self: Box<Self>, input: &[u8], queued: Duration) {
+        (*self)(input, queued)
     }
 }
 
@@ -149,6 +281,11 @@ enum Call {
         message_month: String,
     },
 
+    DeleteMessages {
+        message_month: String,
+        messages: Vec<protocol::Notification>,
+    },
+
     IncStoragePosition {
         uaid: String,
         message_month: String,
@@ -164,6 +301,87 @@ enum Call {
         messages: Vec<protocol::Notification>,
     },
 
+    StoreReceipt {
+        uaid: String,
+        message_id: String,
+        channel_id: String,
+        delivered_at: i64,
+    },
+
+    MintSubscription { uaid: String },
+
+    ResolveSubscription { subscription: String },
+
+    AckMessage {
+        subscription: String,
+        version: String,
+    },
+}
+
+/// Which lane of the queue to Python a call waits in
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Priority {
+    /// Calls a client is waiting on to carry on
+    Interactive = 0,
+    /// Calls nothing's blocked on, taken after interactive ones
+    Background = 1,
+}
+
+impl Priority {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Priority::Interactive => "interactive",
+            Priority::Background => "background",
+        }
+    }
+}
+
+impl Call {
+    fn priority(&self) -> Priority {
+        match *self {
+            Call::StoreMessages { .. } |
+            Call::MigrateUser { .. } |
+            Call::DropUser { .. } |
+            Call::StoreReceipt { .. } => Priority::Background,
+            _ => Priority::Interactive,
+        }
+    }
+
+    /// Whether running this call again after a failure has the same effect
+    /// as running it once, so it may be retried under the default policy
+    fn idempotent(&self) -> bool {
+        match *self {
+            Call::CheckStorage { .. } |
+            Call::DeleteMessage { .. } |
+            Call::DeleteMessages { .. } |
+            Call::IncStoragePosition { .. } |
+            Call::DropUser { .. } |
+            Call::StoreMessages { .. } |
+            Call::ResolveSubscription { .. } |
+            Call::AckMessage { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// The name of this call's command, as seen by Python
+    fn command(&self) -> &'static str {
+        match *self {
+            Call::Hello { .. } => "hello",
+            Call::Register { .. } => "register",
+            Call::Unregister { .. } => "unregister",
+            Call::CheckStorage { .. } => "check_storage",
+            Call::DeleteMessage { .. } => "delete_message",
+            Call::DeleteMessages { .. } => "delete_messages",
+            Call::IncStoragePosition { .. } => "inc_storage_position",
+            Call::DropUser { .. } => "drop_user",
+            Call::MigrateUser { .. } => "migrate_user",
+            Call::StoreMessages { .. } => "store_messages",
+            Call::StoreReceipt { .. } => "store_receipt",
+            Call::MintSubscription { .. } => "mint_subscription",
+            Call::ResolveSubscription { .. } => "resolve_subscription",
+            Call::AckMessage { .. } => "ack_message",
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -218,6 +436,12 @@ pub struct DeleteMessageResponse {
     pub success: bool,
 }
 
+#[derive(Deserialize)]
+pub struct DeleteMessagesResponse {
+    /// Whether each message was deleted, in the order they were sent
+    pub results: Vec<bool>,
+}
+
 #[derive(Deserialize)]
 pub struct IncStorageResponse {
     pub success: bool,
@@ -238,20 +462,43 @@ pub struct StoreMessagesResponse {
     pub success: bool,
 }
 
+#[derive(Deserialize)]
+pub struct StoreReceiptResponse {
+    pub success: bool,
+}
+
+#[derive(Deserialize)]
+pub struct MintSubscriptionResponse {
+    /// Token naming the subscription resource, unguessable without the
+    /// server's key
+    pub subscription: String,
+}
+
+#[derive(Deserialize)]
+pub struct ResolveSubscriptionResponse {
+    /// `None` if the token is invalid or the user is gone
+    pub uaid: Option<Uuid>,
+    /// The user's current message table
+    pub message_month: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct AckMessageResponse {
+    pub deleted: bool,
+}
+
 
 impl Server {
     pub fn hello(&self, connected_at: &u64, uaid: Option<&Uuid>) -> MyFuture<HelloResponse> {
         let ms = *connected_at as i64;
-        let (call, fut) = PythonCall::new(&Call::Hello {
+        self.send_to_python(Call::Hello {
             connected_at: ms,
             uaid: if let Some(uuid) = uaid {
                 Some(uuid.simple().to_string())
             } else {
                 None
             },
-        });
-        self.send_to_python(call);
-        return fut;
+        })
     }
 
     pub fn register(
@@ -261,14 +508,12 @@ impl Server {
         channel_id: String,
         key: Option<String>,
     ) -> MyFuture<RegisterResponse> {
-        let (call, fut) = PythonCall::new(&Call::Register {
+        self.send_to_python(Call::Register {
             uaid: uaid,
             message_month: message_month,
             channel_id: channel_id,
             key: key,
-        });
-        self.send_to_python(call);
-        return fut;
+        })
     }
 
     pub fn unregister(
@@ -278,14 +523,12 @@ impl Server {
         channel_id: String,
         code: i32,
     ) -> MyFuture<UnRegisterResponse> {
-        let (call, fut) = PythonCall::new(&Call::Unregister {
+        self.send_to_python(Call::Unregister {
             uaid: uaid,
             message_month: message_month,
             channel_id: channel_id,
             code: code,
-        });
-        self.send_to_python(call);
-        return fut;
+        })
     }
 
     pub fn check_storage(
@@ -295,14 +538,12 @@ impl Server {
         include_topic: bool,
         timestamp: Option<i64>,
     ) -> MyFuture<CheckStorageResponse> {
-        let (call, fut) = PythonCall::new(&Call::CheckStorage {
+        self.send_to_python(Call::CheckStorage {
             uaid: uaid,
             message_month: message_month,
             include_topic: include_topic,
             timestamp: timestamp,
-        });
-        self.send_to_python(call);
-        return fut;
+        })
     }
 
     pub fn increment_storage(
@@ -311,13 +552,11 @@ impl Server {
         message_month: String,
         timestamp: i64,
     ) -> MyFuture<IncStorageResponse> {
-        let (call, fut) = PythonCall::new(&Call::IncStoragePosition {
+        self.send_to_python(Call::IncStoragePosition {
             uaid: uaid,
             message_month: message_month,
             timestamp: timestamp,
-        });
-        self.send_to_python(call);
-        return fut;
+        })
     }
 
     pub fn delete_message(
@@ -325,18 +564,26 @@ impl Server {
         message_month: String,
         notif: protocol::Notification,
     ) -> MyFuture<DeleteMessageResponse> {
-        let (call, fut) = PythonCall::new(&Call::DeleteMessage {
+        self.send_to_python(Call::DeleteMessage {
             message: notif,
             message_month: message_month,
-        });
-        self.send_to_python(call);
-        return fut;
+        })
+    }
+
+    /// Deletes many messages in a single round trip to Python
+    pub fn delete_messages(
+        &self,
+        message_month: String,
+        messages: Vec<protocol::Notification>,
+    ) -> MyFuture<DeleteMessagesResponse> {
+        self.send_to_python(Call::DeleteMessages {
+            message_month,
+            messages,
+        })
     }
 
     pub fn drop_user(&self, uaid: String) -> MyFuture<DropUserResponse> {
-        let (call, fut) = PythonCall::new(&Call::DropUser { uaid });
-        self.send_to_python(call);
-        return fut;
+        self.send_to_python(Call::DropUser { uaid })
     }
 
     pub fn migrate_user(
@@ -344,12 +591,10 @@ impl Server {
         uaid: String,
         message_month: String,
     ) -> MyFuture<MigrateUserResponse> {
-        let (call, fut) = PythonCall::new(&Call::MigrateUser {
+        self.send_to_python(Call::MigrateUser {
             uaid,
             message_month,
-        });
-        self.send_to_python(call);
-        return fut;
+        })
     }
 
     pub fn store_messages(
@@ -361,44 +606,320 @@ impl Server {
         for message in messages.iter_mut() {
             message.uaid = Some(uaid.clone());
         }
-        let (call, fut) = PythonCall::new(&Call::StoreMessages {
+        self.send_to_python(Call::StoreMessages {
             message_month,
             messages,
-        });
-        self.send_to_python(call);
-        return fut;
+        })
+    }
+
+    pub fn store_receipt(
+        &self,
+        uaid: String,
+        receipt: Receipt,
+    ) -> MyFuture<StoreReceiptResponse> {
+        self.send_to_python(Call::StoreReceipt {
+            uaid,
+            message_id: receipt.message_id,
+            channel_id: receipt.channel_id.hyphenated().to_string(),
+            delivered_at: receipt.delivered_at,
+        })
     }
 
-    fn send_to_python(&self, call: PythonCall) {
-        self.tx.send(Some(call)).expect("python went away?");
+    /// Issues the token naming the push service subscription resource of
+    /// `uaid`
+    pub fn mint_subscription(&self, uaid: &Uuid) -> MyFuture<MintSubscriptionResponse> {
+        self.send_to_python(Call::MintSubscription {
+            uaid: uaid.simple().to_string(),
+        })
     }
-}
 
-impl PythonCall {
-    fn new<T, U>(input: &T) -> (PythonCall, MyFuture<U>)
+    /// Looks up the user and message table behind a subscription token
+    pub fn resolve_subscription(
+        &self,
+        subscription: String,
+    ) -> MyFuture<ResolveSubscriptionResponse> {
+        self.send_to_python(Call::ResolveSubscription { subscription })
+    }
+
+    /// Deletes the stored message `version` of the subscription's user
+    pub fn ack_message(
+        &self,
+        subscription: String,
+        version: String,
+    ) -> MyFuture<AckMessageResponse> {
+        self.send_to_python(Call::AckMessage {
+            subscription,
+            version,
+        })
+    }
+
+    fn send_to_python<U>(&self, input: Call) -> MyFuture<U>
     where
-        T: ser::Serialize,
         U: for<'de> de::Deserialize<'de> + 'static,
     {
+        let command = input.command();
+        let priority = input.priority();
+        let idempotent = input.idempotent();
+        let encoding = self.opts.call_encoding;
+        let input = match encoding.encode(&input) {
+            Ok(input) => input,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        let policy = self.opts
+            .call_retries
+            .get(command)
+            .or_else(|| if idempotent {
+                self.opts.call_retries.get("default")
+            } else {
+                None
+            })
+            .cloned();
+        let attempts = policy.as_ref().map_or(1, |p| cmp::max(p.attempts, 1));
+        let deadline = self.opts
+            .call_timeouts
+            .get(command)
+            .or_else(|| self.opts.call_timeouts.get("default"))
+            .cloned();
+
+        let tx = self.tx.clone();
+        let handle = self.handle.clone();
+        let metrics = self.metrics.clone();
+        let calls = future::loop_fn(0, move |attempt| -> MyFuture<Loop<Vec<u8>, u32>> {
+            let (call, rx) = PythonCall::new(input.clone(), encoding, priority);
+            let cancelled = call.cancelled.clone();
+            if let Err(e) = tx.send(call) {
+                return Box::new(future::err(e));
+            }
+            for lane in [Priority::Interactive, Priority::Background].iter() {
+                metrics
+                    .gauge_with_tags("python.queue.depth", tx.depth(*lane) as u64)
+                    .with_tag("lane", lane.name())
+                    .send()
+                    .ok();
+            }
+
+            let rx: MyFuture<Completion> =
+                Box::new(rx.map_err(|_| ErrorKind::PythonCanceled.into()));
+            let rx = match deadline {
+                Some(deadline) => with_deadline(rx, deadline, cancelled, &handle),
+                None => rx,
+            };
+
+            let handle = handle.clone();
+            let metrics = metrics.clone();
+            let policy = policy.clone();
+            Box::new(rx.then(move |res| -> MyFuture<Loop<Vec<u8>, u32>> {
+                let res = match res {
+                    Ok(Completion { output, queued }) => {
+                        metrics
+                            .time_duration_with_tags("python.call.queued", queued)
+                            .with_tag("command", command)
+                            .send()
+                            .ok();
+                        output
+                    }
+                    Err(e) => Err(e),
+                };
+                let e = match res {
+                    Ok(output) => return Box::new(future::ok(Loop::Break(output))),
+                    Err(e) => e,
+                };
+                // Only failures reported by Python itself are worth retrying,
+                // a canceled call won't be picked up the next time either
+                let retry = match *e.kind() {
+                    ErrorKind::PythonException(_) => attempt + 1 < attempts,
+                    _ => false,
+                };
+                if !retry {
+                    return Box::new(future::err(e));
+                }
+                debug!("Retrying {} call: {}", command, e);
+                metrics
+                    .incr_with_tags("python.call.retry")
+                    .with_tag("command", command)
+                    .send()
+                    .ok();
+                let delay = policy.unwrap().delay(attempt);
+                match Timeout::new(delay, &handle) {
+                    Ok(timeout) => Box::new(
+                        timeout
+                            .map(move |()| Loop::Continue(attempt + 1))
+                            .chain_err(|| "python call retry timer failed"),
+                    ),
+                    Err(e) => Box::new(future::err(e.into())),
+                }
+            }))
+        });
+
+        let metrics = self.metrics.clone();
+        let breaker = self.breaker.clone();
+        let start = Instant::now();
+        Box::new(calls.then(move |res| -> Result<U> {
+            metrics
+                .time_duration_with_tags("python.call.time", start.elapsed())
+                .with_tag("command", command)
+                .send()
+                .ok();
+            let error = match res {
+                Ok(_) => None,
+                Err(ref e) => Some(match *e.kind() {
+                    ErrorKind::PythonException(_) => "python exception",
+                    ErrorKind::PythonCanceled => "call canceled from python",
+                    ErrorKind::PythonTimeout => "timeout",
+                    ErrorKind::PythonQueueFull => "queue full",
+                    _ => "other",
+                }),
+            };
+            if let Some(error) = error {
+                metrics
+                    .incr_with_tags("python.call.error")
+                    .with_tag("command", command)
+                    .with_tag("error", error)
+                    .send()
+                    .ok();
+            }
+            // Only the backend failing counts against it, not calls that
+            // timed out or never made it through our own queue
+            match res {
+                Ok(_) => breaker.record(true, &metrics),
+                Err(ref e) => if let ErrorKind::PythonException(_) = *e.kind() {
+                    breaker.record(false, &metrics)
+                },
+            }
+            encoding.decode(&res?)
+        }))
+    }
+}
+
+/// Resolves to the completion of a call, unless that takes longer than
+/// `deadline`. The call is then marked as `cancelled` and its completion will
+/// be dropped, should Python still get around to it.
+fn with_deadline(
+    rx: MyFuture<Completion>,
+    deadline: Duration,
+    cancelled: Arc<AtomicBool>,
+    handle: &Handle,
+) -> MyFuture<Completion> {
+    let timeout = match Timeout::new(deadline, handle) {
+        Ok(timeout) => timeout,
+        Err(e) => return Box::new(future::err(e.into())),
+    };
+    Box::new(rx.select2(timeout).then(move |res| match res {
+        Ok(Either::A((completion, _timeout))) => Ok(completion),
+        Err(Either::A((e, _timeout))) => Err(e),
+        Ok(Either::B(((), _rx))) => {
+            cancelled.store(true, Ordering::SeqCst);
+            Err(ErrorKind::PythonTimeout.into())
+        }
+        Err(Either::B((e, _rx))) => Err(e.into()),
+    }))
+}
+
+/// How failed calls of a command are retried
+#[derive(Deserialize, Clone)]
+pub struct RetryPolicy {
+    /// Attempts made in total, including the first one
+    pub attempts: u32,
+    /// Seconds to wait before the first retry, doubling with each retry
+    pub base_delay: f64,
+    /// Most seconds to wait between two attempts
+    pub max_delay: f64,
+}
+
+impl RetryPolicy {
+    /// How long to wait after the `attempt`th attempt (counting from 0)
+    /// failed, randomly picked between half and all of the backoff so retries
+    /// of calls that failed together spread out.
+    fn delay(&self, attempt: u32) -> Duration {
+        let backoff = self.base_delay * 2f64.powi(attempt as i32);
+        let backoff = backoff.min(self.max_delay).max(0.0);
+        let jitter = rand::random::<f64>();
+        let delay = backoff * (0.5 + jitter / 2.0);
+        Duration::new(delay as u64, (delay.fract() * 1_000_000_000.0) as u32)
+    }
+}
+
+/// Keeps track of calls into Python failing with a backend error, opening up
+/// once `threshold` calls have failed in a row.
+///
+/// While open, which it stays for `cooldown`, new clients are turned away
+/// rather than adding to the load of a backend that's already struggling.
+/// After that calls are let through again, the first success closing the
+/// breaker and another failure opening it right back up.
+pub struct CircuitBreaker {
+    threshold: u32,
+    cooldown: Duration,
+    failures: Cell<u32>,
+    opened_at: Cell<Option<Instant>>,
+}
+
+impl CircuitBreaker {
+    /// A `threshold` of 0 never opens the breaker.
+    pub fn new(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            threshold,
+            cooldown,
+            failures: Cell::new(0),
+            opened_at: Cell::new(None),
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        match self.opened_at.get() {
+            Some(at) => at.elapsed() < self.cooldown,
+            None => false,
+        }
+    }
+
+    fn record(&self, success: bool, metrics: &StatsdClient) {
+        if success {
+            self.failures.set(0);
+            self.opened_at.set(None);
+            return;
+        }
+        let failures = self.failures.get().saturating_add(1);
+        self.failures.set(failures);
+        if self.threshold > 0 && failures >= self.threshold && !self.is_open() {
+            warn!("Too many failed python calls, turning away new clients");
+            metrics.incr("python.breaker.open").ok();
+            self.opened_at.set(Some(Instant::now()));
+        }
+    }
+}
+
+impl PythonCall {
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
+    fn new(
+        input: Vec<u8>,
+        encoding: CallEncoding,
+        priority: Priority,
+    ) -> (PythonCall, oneshot::Receiver<Completion>) {
         let (tx, rx) = oneshot::channel();
         let call = PythonCall {
-            input: serde_json::to_string(input).unwrap(),
-            output: Box::new(|json: &str| { drop(tx.send(json_or_error(json))); }),
+            input: input,
+            encoding: encoding,
+            priority: priority,
+            queued_at: Instant::now(),
+            cancelled: Arc::new(AtomicBool::new(false)),
+            output: Box::new(move |output: &[u8], queued: Duration| {
+                drop(tx.send(Completion {
+                    output: output_or_error(encoding, output),
+                    queued: queued,
+                }));
+            }),
         };
-        let rx = Box::new(rx.then(|res| match res {
-            Ok(Ok(s)) => Ok(serde_json::from_str(&s)?),
-            Ok(Err(e)) => Err(e),
-            Err(_) => Err("call canceled from python".into()),
-        }));
         (call, rx)
     }
 }
 
-fn json_or_error(json: &str) -> Result<String> {
-    if let Ok(err) = serde_json::from_str::<PythonError>(json) {
+fn output_or_error(encoding: CallEncoding, output: &[u8]) -> Result<Vec<u8>> {
+    if let Ok(err) = encoding.decode::<PythonError>(output) {
         if err.error {
-            return Err(format!("python exception: {}", err.error_msg).into());
+            return Err(ErrorKind::PythonException(err.error_msg).into());
         }
     }
-    Ok(json.to_string())
+    Ok(output.to_vec())
 }
@@ -5,6 +5,7 @@
 //! of connected clients. Note that it's expected there'll be a lot of connected
 //! clients, so this may appears relatively heavily optimized!
 
+use std::cell::Cell;
 use std::rc::Rc;
 
 use cadence::prelude::*;
@@ -20,12 +21,18 @@ use woothee::parser::{Parser, WootheeResult};
 
 use call;
 use errors::*;
-use protocol::{ClientAck, ClientMessage, ServerMessage, ServerNotification, Notification};
+use protocol::{ClientAck, ClientMessage, ServerMessage, ServerNotification, Notification, Urgency};
 use server::Server;
+use spool;
 
 pub struct RegisteredClient {
     pub uaid: Uuid,
-    pub tx: mpsc::UnboundedSender<ServerNotification>,
+    /// Holds at most `client_queue_depth` notifications (plus one slot per
+    /// sender) that the client hasn't picked up yet
+    pub tx: mpsc::Sender<ServerNotification>,
+    /// Set when a storage check couldn't be queued as `tx` was full, for the
+    /// client to pick up once it's through with its queue
+    pub check_pending: Rc<Cell<bool>>,
 }
 
 // Websocket session statistics
@@ -58,14 +65,46 @@ pub struct ClientData<T> {
     webpush: Option<WebPushClient>,
     srv: Rc<Server>,
     ws: T,
-    user_agent: String,
+    user_agent: UserAgentInfo,
     host: String,
 }
 
+// Parsed out bits of the client's user-agent, used for logging and as metric
+// tags
+#[derive(Default)]
+struct UserAgentInfo {
+    name: String,
+    os_family: String,
+    os_ver: String,
+    browser_family: String,
+    browser_ver: String,
+    category: String,
+}
+
+impl UserAgentInfo {
+    fn parse(user_agent: &str) -> UserAgentInfo {
+        let parser = Parser::new();
+        match parser.parse(user_agent) {
+            Some(WootheeResult { name, os, os_version, version, vendor, category, .. }) => {
+                UserAgentInfo {
+                    name: String::from(name),
+                    os_family: String::from(os),
+                    os_ver: os_version,
+                    browser_family: String::from(vendor),
+                    browser_ver: version,
+                    category: String::from(category),
+                }
+            }
+            None => UserAgentInfo::default(),
+        }
+    }
+}
+
 // Represent the state for a valid WebPush client that is authenticated
 pub struct WebPushClient {
     uaid: Uuid,
-    rx: mpsc::UnboundedReceiver<ServerNotification>,
+    rx: mpsc::Receiver<ServerNotification>,
+    check_pending: Rc<Cell<bool>>,
     flags: ClientFlags,
     message_month: String,
     unacked_direct_notifs: Vec<Notification>,
@@ -73,6 +112,12 @@ pub struct WebPushClient {
     // Highest version from stored, retained for use with increment
     // when all the unacked storeds are ack'd
     unacked_stored_highest: Option<i64>,
+    // Least urgent notifications the client wants pushed right now
+    min_urgency: Urgency,
+    // Whether notifications were left in storage for being below
+    // `min_urgency`, in which case the storage position can't be moved past
+    // them and acked stored notifications are deleted instead
+    deferred: bool,
     connected_at: u64,
     stats: SessionStatistics,
 }
@@ -123,7 +168,8 @@ pub enum ClientState {
     WaitingForRegister(Uuid, MyFuture<call::RegisterResponse>),
     WaitingForUnRegister(Uuid, MyFuture<call::UnRegisterResponse>),
     WaitingForCheckStorage(MyFuture<call::CheckStorageResponse>),
-    WaitingForDelete(MyFuture<call::DeleteMessageResponse>),
+    WaitingForDelete(MyFuture<call::DeleteMessagesResponse>),
+    WaitingForStore(MyFuture<call::StoreMessagesResponse>),
     WaitingForIncrementStorage(MyFuture<call::IncStorageResponse>),
     WaitingForDropUser(MyFuture<call::DropUserResponse>),
     WaitingForMigrateUser(MyFuture<call::MigrateUserResponse>),
@@ -137,6 +183,32 @@ pub enum ClientState {
     ShutdownCleanup(Option<Error>),
 }
 
+impl ClientState {
+    /// The name this state is tallied under in server stats
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ClientState::WaitingForHello(..) => "WaitingForHello",
+            ClientState::WaitingForProcessHello(..) => "WaitingForProcessHello",
+            ClientState::WaitingForRegister(..) => "WaitingForRegister",
+            ClientState::WaitingForUnRegister(..) => "WaitingForUnRegister",
+            ClientState::WaitingForCheckStorage(..) => "WaitingForCheckStorage",
+            ClientState::WaitingForDelete(..) => "WaitingForDelete",
+            ClientState::WaitingForStore(..) => "WaitingForStore",
+            ClientState::WaitingForIncrementStorage(..) => "WaitingForIncrementStorage",
+            ClientState::WaitingForDropUser(..) => "WaitingForDropUser",
+            ClientState::WaitingForMigrateUser(..) => "WaitingForMigrateUser",
+            ClientState::FinishSend(..) => "FinishSend",
+            ClientState::SendMessages(..) => "SendMessages",
+            ClientState::CheckStorage => "CheckStorage",
+            ClientState::IncrementStorage => "IncrementStorage",
+            ClientState::WaitingForAcks => "WaitingForAcks",
+            ClientState::Await => "Await",
+            ClientState::Done => "Done",
+            ClientState::ShutdownCleanup(..) => "ShutdownCleanup",
+        }
+    }
+}
+
 impl<T> Client<T>
 where
     T: Stream<Item = ClientMessage, Error = Error>
@@ -170,13 +242,15 @@ where
             }
         };
 
+        let state = ClientState::WaitingForHello(timeout);
+        srv.stats.client_entered(state.name());
         Client {
-            state: ClientState::WaitingForHello(timeout),
+            state: state,
             data: ClientData {
                 webpush: None,
                 srv: srv.clone(),
                 ws: ws,
-                user_agent: uastr,
+                user_agent: UserAgentInfo::parse(&uastr),
                 host,
             },
         }
@@ -186,6 +260,13 @@ where
         self.data.shutdown();
     }
 
+    fn set_state(&mut self, state: ClientState) {
+        let stats = &self.data.srv.stats;
+        stats.client_left(self.state.name());
+        stats.client_entered(state.name());
+        self.state = state;
+    }
+
     fn transition(&mut self) -> Poll<ClientState, Error> {
         let host = self.data.host.clone();
         let next_state = match self.state {
@@ -219,15 +300,23 @@ where
                         if let Some(_) = message.topic {
                             self.data.srv.metrics.incr("ua.notification.topic")?;
                         }
-                        // XXX: tags
-                        self.data.srv.metrics.count(
-                            "ua.message_data",
-                            message.data.as_ref().map_or(0, |d| {
-                                d.len() as i64
-                            }),
-                        )?;
+                        let webpush = self.data.webpush.as_ref().unwrap();
+                        let ua = &self.data.user_agent;
+                        self.data.srv.metrics
+                            .count_with_tags(
+                                "ua.message_data",
+                                message.data.as_ref().map_or(0, |d| {
+                                    d.len() as i64
+                                }),
+                            )
+                            .with_tag("ua_browser_family", &ua.browser_family)
+                            .with_tag("ua_os_family", &ua.os_family)
+                            .with_tag("connection_type", &webpush.stats.connection_type)
+                            .with_tag("host", &self.data.srv.opts.host_ip)
+                            .with_tag("topic", if message.topic.is_some() { "true" } else { "false" })
+                            .send()?;
                         ClientState::FinishSend(
-                            Some(ServerMessage::Notification(message)),
+                            Some(ServerMessage::notification(message)),
                             Some(Box::new(ClientState::SendMessages(if messages.len() > 0 {
                                 Some(messages)
                             } else {
@@ -274,6 +363,9 @@ where
                     } => uaid,
                     _ => return Err("Invalid message, must be hello".into()),
                 };
+                if self.data.srv.breaker.is_open() || self.data.srv.tx.is_saturated() {
+                    return Ok(overloaded(&self.data.srv).into());
+                }
                 let connected_at = time::precise_time_ns() / 1000;
                 ClientState::WaitingForProcessHello(
                     self.data.srv.hello(&connected_at, uaid.as_ref()),
@@ -281,7 +373,13 @@ where
             }
             ClientState::WaitingForProcessHello(ref mut response) => {
                 debug!("State: WaitingForProcessHello");
-                match try_ready!(response.poll()) {
+                let response = match response.poll() {
+                    Err(Error(ErrorKind::PythonQueueFull, _)) => {
+                        return Ok(overloaded(&self.data.srv).into())
+                    }
+                    res => try_ready!(res),
+                };
+                match response {
                     call::HelloResponse {
                         uaid: Some(uaid),
                         message_month,
@@ -317,12 +415,27 @@ where
                 let webpush = self.data.webpush.as_mut().unwrap();
                 webpush.flags.include_topic = include_topic;
                 webpush.unacked_stored_highest = timestamp;
+
+                // Leave anything below the client's urgency threshold where
+                // it is. Deferred topic messages would be returned again on
+                // every check, so move on to the timestamped ones.
+                let fetched = messages.len();
+                let min_urgency = webpush.min_urgency;
+                messages.retain(|m| m.urgency() >= min_urgency);
+                if messages.len() < fetched {
+                    webpush.deferred = true;
+                    webpush.flags.include_topic = false;
+                    if messages.is_empty() {
+                        return Ok(ClientState::CheckStorage.into());
+                    }
+                }
+
                 if messages.len() > 0 {
-                    webpush.flags.increment_storage = !include_topic;
+                    webpush.flags.increment_storage = !include_topic && !webpush.deferred;
                     webpush.unacked_stored_notifs.extend(
                         messages.iter().cloned(),
                     );
-                    let message = ServerMessage::Notification(messages.pop().unwrap());
+                    let message = ServerMessage::notification(messages.pop().unwrap());
                     ClientState::FinishSend(
                         Some(message),
                         Some(Box::new(ClientState::SendMessages(Some(messages)))),
@@ -416,11 +529,21 @@ where
                         ClientState::WaitingForAcks
                     }
                     ClientMessage::Ack { updates } => self.data.process_acks(updates),
+                    ClientMessage::Urgency { min } => self.data.process_urgency(min),
                     _ => return Err("Invalid state transition".into()),
                 }
             }
             ClientState::WaitingForDelete(ref mut response) => {
                 debug!("State: WaitingForDelete");
+                let call::DeleteMessagesResponse { results } = try_ready!(response.poll());
+                let failed = results.iter().filter(|deleted| !**deleted).count();
+                if failed > 0 {
+                    debug!("Failed to delete {} acked messages", failed);
+                }
+                ClientState::WaitingForAcks
+            }
+            ClientState::WaitingForStore(ref mut response) => {
+                debug!("State: WaitingForStore");
                 try_ready!(response.poll());
                 ClientState::WaitingForAcks
             }
@@ -446,12 +569,26 @@ where
                         self.data.webpush.as_mut().unwrap().stats.nacks += 1;
                         ClientState::WaitingForAcks
                     }
+                    Either::A(ClientMessage::Urgency { min }) => self.data.process_urgency(min),
+                    Either::B(ServerNotification::Notification(ref notif))
+                        if notif.urgency() < self.data.webpush.as_ref().unwrap().min_urgency =>
+                    {
+                        debug!("Deferring a notification below the client's urgency");
+                        let webpush = self.data.webpush.as_mut().unwrap();
+                        webpush.deferred = true;
+                        webpush.stats.direct_storage += 1;
+                        ClientState::WaitingForStore(self.data.srv.store_messages(
+                            webpush.uaid.simple().to_string(),
+                            webpush.message_month.clone(),
+                            vec![notif.clone()],
+                        ))
+                    }
                     Either::B(ServerNotification::Notification(notif)) => {
                         let webpush = self.data.webpush.as_mut().unwrap();
                         webpush.unacked_direct_notifs.push(notif.clone());
                         debug!("Got a notification to send, sending!");
                         ClientState::FinishSend(
-                            Some(ServerMessage::Notification(notif)),
+                            Some(ServerMessage::notification(notif)),
                             Some(Box::new(ClientState::WaitingForAcks)),
                         )
                     }
@@ -488,6 +625,19 @@ where
     }
 }
 
+/// Turns the client away as the server is too busy to take it on
+fn overloaded(srv: &Server) -> ClientState {
+    srv.metrics.incr("ua.command.hello.overloaded").ok();
+    let response = ServerMessage::Error {
+        reason: "overloaded".to_string(),
+        status: 503,
+    };
+    ClientState::FinishSend(
+        Some(response),
+        Some(Box::new(ClientState::ShutdownCleanup(None))),
+    )
+}
+
 impl<T> ClientData<T>
 where
     T: Stream<Item = ClientMessage, Error = Error>
@@ -522,6 +672,9 @@ where
         let item = match webpush.rx.poll() {
             Ok(Async::Ready(Some(notif))) => Either::B(notif),
             Ok(Async::Ready(None)) => return Err("Sending side dropped".into()),
+            Ok(Async::NotReady) if webpush.check_pending.replace(false) => {
+                Either::B(ServerNotification::CheckStorage)
+            }
             Ok(Async::NotReady) => {
                 match self.ws.poll()? {
                     Async::Ready(None) => return Err("Client dropped".into()),
@@ -543,7 +696,8 @@ where
         check_storage: bool,
         connected_at: u64,
     ) -> ClientState {
-        let (tx, rx) = mpsc::unbounded();
+        let (tx, rx) = mpsc::channel(self.srv.opts.client_queue_depth);
+        let check_pending = Rc::new(Cell::new(false));
         let mut flags = ClientFlags::new();
         flags.check = check_storage;
         flags.reset_uaid = reset_uaid;
@@ -553,10 +707,13 @@ where
             uaid,
             flags,
             rx,
+            check_pending: check_pending.clone(),
             message_month,
             unacked_direct_notifs: Vec::new(),
             unacked_stored_notifs: Vec::new(),
             unacked_stored_highest: None,
+            min_urgency: Urgency::VeryLow,
+            deferred: false,
             connected_at,
             stats: SessionStatistics {
                 uaid: uaid.hyphenated().to_string(),
@@ -574,7 +731,11 @@ where
             },
         });
         self.srv.connect_client(
-            RegisteredClient { uaid: uaid, tx: tx },
+            RegisteredClient {
+                uaid: uaid,
+                tx: tx,
+                check_pending: check_pending,
+            },
         );
         let response = ServerMessage::Hello {
             uaid: uaid.hyphenated().to_string(),
@@ -614,17 +775,44 @@ where
         ClientState::WaitingForUnRegister(channel_id, fut)
     }
 
+    fn process_urgency(&mut self, min: Urgency) -> ClientState {
+        debug!("Got an urgency command"; "min" => format!("{:?}", min));
+        self.srv.metrics.incr("ua.command.urgency").ok();
+        let webpush = self.webpush.as_mut().unwrap();
+        let lowered = min < webpush.min_urgency;
+        webpush.min_urgency = min;
+
+        // Go back over storage from the last position saved for the client,
+        // which is still before anything we left there.
+        if lowered && webpush.deferred {
+            webpush.deferred = false;
+            webpush.unacked_stored_highest = None;
+            webpush.flags.include_topic = true;
+            webpush.flags.check = true;
+        }
+        let next_state = if webpush.unacked_messages() {
+            ClientState::WaitingForAcks
+        } else {
+            ClientState::Await
+        };
+        ClientState::FinishSend(
+            Some(ServerMessage::Urgency { status: 200 }),
+            Some(Box::new(next_state)),
+        )
+    }
+
     fn process_acks(&mut self, updates: Vec<ClientAck>) -> ClientState {
         self.srv.metrics.incr("ua.command.ack").ok();
         let webpush = self.webpush.as_mut().unwrap();
-        let mut fut: Option<MyFuture<call::DeleteMessageResponse>> = None;
+        let mut to_delete = Vec::new();
         for notif in updates.iter() {
             if let Some(pos) = webpush.unacked_direct_notifs.iter().position(|v| {
                 v.channel_id == notif.channel_id && v.version == notif.version
             })
             {
                 webpush.stats.direct_acked += 1;
-                webpush.unacked_direct_notifs.remove(pos);
+                let n = webpush.unacked_direct_notifs.remove(pos);
+                self.srv.send_receipt(&webpush.uaid, &n);
                 continue;
             };
             if let Some(pos) = webpush.unacked_stored_notifs.iter().position(|v| {
@@ -632,23 +820,19 @@ where
             })
             {
                 webpush.stats.stored_acked += 1;
-                let message_month = webpush.message_month.clone();
                 let n = webpush.unacked_stored_notifs.remove(pos);
-                if n.topic.is_some() {
-                    if fut.is_none() {
-                        fut = Some(self.srv.delete_message(message_month, n))
-                    } else {
-                        let my_fut = self.srv.delete_message(message_month, n);
-                        fut = Some(Box::new(fut.take().unwrap().and_then(move |_| my_fut)));
-                    }
+                self.srv.send_receipt(&webpush.uaid, &n);
+                if n.topic.is_some() || webpush.deferred {
+                    to_delete.push(n);
                 }
                 continue;
             };
         }
-        if let Some(my_fut) = fut {
-            ClientState::WaitingForDelete(my_fut)
-        } else {
+        if to_delete.is_empty() {
             ClientState::WaitingForAcks
+        } else {
+            let message_month = webpush.message_month.clone();
+            ClientState::WaitingForDelete(self.srv.delete_messages(message_month, to_delete))
         }
     }
 
@@ -688,8 +872,14 @@ where
             let webpush = self.webpush.take().unwrap();
             let now = time::precise_time_ns() / 1000;
             let elapsed = now - webpush.connected_at;
-            // XXX: tags
-            self.srv.metrics.time("ua.connection.lifespan", elapsed).ok();
+            self.srv.metrics
+                .time_with_tags("ua.connection.lifespan", elapsed)
+                .with_tag("ua_browser_family", &self.user_agent.browser_family)
+                .with_tag("ua_os_family", &self.user_agent.os_family)
+                .with_tag("connection_type", &webpush.stats.connection_type)
+                .with_tag("host", &self.srv.opts.host_ip)
+                .send()
+                .ok();
 
             // If there's direct unack'd messages, they need to be saved out without blocking
             // here
@@ -698,41 +888,14 @@ where
             let unacked_direct_notifs = webpush.unacked_direct_notifs.len();
             if unacked_direct_notifs > 0 {
                 stats.direct_storage += unacked_direct_notifs as i32;
-                self.srv.handle.spawn(
-                    self.srv
-                        .store_messages(
-                            webpush.uaid.simple().to_string(),
-                            webpush.message_month,
-                            webpush.unacked_direct_notifs,
-                        )
-                        .then(|_| {
-                            debug!("Finished saving unacked direct notifications");
-                            Ok(())
-                        }),
-                )
+                spool::store_or_spool(
+                    &self.srv,
+                    webpush.uaid.simple().to_string(),
+                    webpush.message_month,
+                    webpush.unacked_direct_notifs,
+                );
             }
 
-            // Parse the user-agent string
-            let parser = Parser::new();
-            let ua_result = parser.parse(self.user_agent.as_str());
-            let mut ua_os_family = String::new();
-            let mut ua_os_ver = String::new();
-            let mut ua_browser_family = String::new();
-            let mut ua_browser_ver = String::new();
-            let mut ua_name = String::new();
-            let mut ua_category = String::new();
-            match ua_result {
-                Some(WootheeResult { name, os, os_version, version, vendor, category, .. }) => {
-                    ua_name = String::from(name);
-                    ua_os_family = String::from(os);
-                    ua_os_ver = os_version;
-                    ua_browser_family = String::from(vendor);
-                    ua_browser_ver = version;
-                    ua_category = String::from(category);
-                }
-                None => ()
-            };
-
             // Log out the final stats message
             info!("Session";
                 "uaid_hash" => stats.uaid.as_str(),
@@ -740,12 +903,12 @@ where
                 "existing_uaid" => stats.existing_uaid,
                 "connection_type" => stats.connection_type.as_str(),
                 "host" => self.host.clone(),
-                "ua_name" => ua_name.as_str(),
-                "ua_os_family" => ua_os_family.as_str(),
-                "ua_os_ver" => ua_os_ver.as_str(),
-                "ua_browser_family" => ua_browser_family.as_str(),
-                "ua_browser_ver" => ua_browser_ver.as_str(),
-                "ua_category" => ua_category.as_str(),
+                "ua_name" => self.user_agent.name.as_str(),
+                "ua_os_family" => self.user_agent.os_family.as_str(),
+                "ua_os_ver" => self.user_agent.os_ver.as_str(),
+                "ua_browser_family" => self.user_agent.browser_family.as_str(),
+                "ua_browser_ver" => self.user_agent.browser_ver.as_str(),
+                "ua_category" => self.user_agent.category.as_str(),
                 "connection_time" => elapsed,
                 "direct_acked" => stats.direct_acked,
                 "direct_storage" => stats.direct_storage,
@@ -775,9 +938,15 @@ where
             }
             match self.transition() {
                 Ok(Async::NotReady) => return Ok(Async::NotReady),
-                Ok(Async::Ready(next_state)) => self.state = next_state,
-                Err(e) => self.state = ClientState::ShutdownCleanup(Some(e)),
+                Ok(Async::Ready(next_state)) => self.set_state(next_state),
+                Err(e) => self.set_state(ClientState::ShutdownCleanup(Some(e))),
             };
         }
     }
 }
+
+impl<T> Drop for Client<T> {
+    fn drop(&mut self) {
+        self.data.srv.stats.client_left(self.state.name());
+    }
+}
@@ -46,6 +46,35 @@ error_chain! {
         Thread(payload: Box<Any + Send>) {
             description("thread panicked")
         }
+
+        PythonException(msg: String) {
+            description("python exception")
+            display("python exception: {}", msg)
+        }
+
+        PythonCanceled {
+            description("call canceled from python")
+        }
+
+        PythonTimeout {
+            description("call into python timed out")
+        }
+
+        PythonQueueFull {
+            description("too many calls waiting on python")
+        }
+
+        ClientNotConnected {
+            description("user not connected")
+        }
+
+        ClientBackpressure {
+            description("client notification queue is full")
+        }
+
+        SpoolFull {
+            description("spool is full")
+        }
     }
 }
 
@@ -6,7 +6,7 @@
 use std::str;
 use std::rc::Rc;
 
-use futures::future::err;
+use futures::future::{err, ok};
 use futures::{Stream, Future};
 use hyper::Method;
 use hyper;
@@ -14,9 +14,14 @@ use serde_json;
 use tokio_service::Service;
 use uuid::Uuid;
 
+use errors::{Error, ErrorKind};
 use server::Server;
 
-pub struct Push(pub Rc<Server>);
+pub struct Push {
+    pub srv: Rc<Server>,
+    /// Whether the peer passed the router's client certificate verification
+    pub authorized: bool,
+}
 
 impl Service for Push {
     type Request = hyper::Request;
@@ -25,16 +30,28 @@ impl Service for Push {
     type Future = Box<Future<Item = hyper::Response, Error = hyper::Error>>;
 
     fn call(&self, req: hyper::Request) -> Self::Future {
+        if !self.authorized {
+            debug!("Rejecting unauthorized router peer");
+            return Box::new(ok(
+                hyper::Response::new().with_status(hyper::StatusCode::Unauthorized),
+            ));
+        }
         if *req.method() != Method::Put && *req.method() != Method::Post {
             println!("not a PUT: {}", req.method());
             return Box::new(err(hyper::Error::Method));
         }
-        if req.uri().path().len() == 0 {
-            println!("empty uri path");
-            return Box::new(err(hyper::Error::Incomplete));
-        }
-        let req_uaid = req.uri().path()[6..].to_string();
-        let uaid = match Uuid::parse_str(&req_uaid) {
+        let path = req.uri().path().to_string();
+        let (check, req_uaid) = if path.starts_with("/notif/") {
+            (true, &path[7..])
+        } else if path.starts_with("/push/") {
+            (false, &path[6..])
+        } else {
+            debug!("unknown uri path: {}", path);
+            return Box::new(ok(
+                hyper::Response::new().with_status(hyper::StatusCode::NotFound),
+            ));
+        };
+        let uaid = match Uuid::parse_str(req_uaid) {
             Ok(id) => id,
             Err(_) => {
                 println!("uri not uuid: {}", req_uaid);
@@ -42,27 +59,40 @@ impl Service for Push {
             }
         };
 
+        // Stored notifications, which the client fetches itself
+        if check {
+            let status = match self.srv.check_client_storage(uaid) {
+                Ok(()) => hyper::StatusCode::Ok,
+                Err(_) => hyper::StatusCode::NotFound,
+            };
+            return Box::new(ok(hyper::Response::new().with_status(status)));
+        }
+
         debug!("Got a message, now to do something!");
 
         let body = req.body().concat2();
-        let srv = self.0.clone();
+        let srv = self.srv.clone();
         Box::new(body.and_then(move |body| {
             let s = String::from_utf8(body.to_vec()).unwrap();
-            if let Ok(msg) = serde_json::from_str(&s) {
-                match srv.notify_client(uaid, msg) {
-                    Ok(_) => return Ok(hyper::Response::new().with_status(hyper::StatusCode::Ok)),
-                    _ => {
-                        return Ok(
-                            hyper::Response::new()
-                                .with_status(hyper::StatusCode::BadRequest)
-                                .with_body("Unable to decode body payload"),
-                        )
-                    }
+            let msg = match serde_json::from_str(&s) {
+                Ok(msg) => msg,
+                Err(_) => {
+                    return Ok(
+                        hyper::Response::new()
+                            .with_status(hyper::StatusCode::BadRequest)
+                            .with_body("Unable to decode body payload"),
+                    )
                 }
-            }
-            Ok(hyper::Response::new().with_status(
-                hyper::StatusCode::NotFound,
-            ))
+            };
+            // Anything but a 200 has the router store the notification
+            let status = match srv.notify_client(uaid, msg) {
+                Ok(()) => hyper::StatusCode::Ok,
+                Err(Error(ErrorKind::ClientBackpressure, _)) => {
+                    hyper::StatusCode::ServiceUnavailable
+                }
+                Err(_) => hyper::StatusCode::NotFound,
+            };
+            Ok(hyper::Response::new().with_status(status))
         }))
     }
 }
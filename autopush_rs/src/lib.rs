@@ -54,21 +54,32 @@
 //!   over websockets.
 //! * `call` - definitions of various calls that can be made into Python, each
 //!   of which returning a future of the response.
+//! * `receipts` - delivery receipts sent once clients acknowledge
+//!   notifications.
+//! * `spool` - an on-disk spool of notifications that failed to be stored,
+//!   replayed until they are.
 //!
 //! Other modules tend to be miscellaneous implementation details and likely
 //! aren't as relevant to the WebPush implementation.
 //!
 //! Otherwise be sure to check out each module for more documentation!
 
+extern crate base64;
 extern crate bytes;
 extern crate cadence;
 extern crate chrono;
+extern crate flate2;
 #[macro_use]
 extern crate futures;
+extern crate futures_cpupool;
 extern crate httparse;
 extern crate hyper;
+extern crate hyper_tls;
 extern crate libc;
+extern crate native_tls;
 extern crate openssl;
+extern crate rand;
+extern crate rmp_serde;
 extern crate sentry;
 extern crate serde;
 #[macro_use]
@@ -100,6 +111,8 @@ mod client;
 mod errors;
 mod http;
 mod protocol;
+mod receipts;
+mod spool;
 mod util;
 
 #[macro_use]
@@ -6,10 +6,56 @@
 //! are used to generate the ability to serialize these structures to JSON,
 //! using the `serde` crate. More docs for serde can be found at
 //! https://serde.rs
+//!
+//! Websocket clients normally exchange these as JSON text frames, but may
+//! negotiate MessagePack binary frames instead through the websocket
+//! subprotocol, see `Encoding`.
 
 use std::collections::HashMap;
+
+use base64;
+use rmp_serde;
+use serde::{Serialize, Serializer};
 use uuid::Uuid;
 
+use errors::*;
+
+/// Websocket subprotocol selecting `Encoding::MessagePack`
+pub const MSGPACK_PROTOCOL: &str = "push-notification-msgpack";
+
+/// How client and server messages are framed on a websocket
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    /// JSON in text frames
+    Json,
+    /// MessagePack in binary frames, with the same schema as JSON except for
+    /// notification `data` being raw bytes instead of base64url
+    MessagePack,
+}
+
+impl Encoding {
+    /// The encoding selected by a negotiated websocket subprotocol
+    pub fn for_protocol(protocol: Option<&str>) -> Encoding {
+        match protocol {
+            Some(MSGPACK_PROTOCOL) => Encoding::MessagePack,
+            _ => Encoding::Json,
+        }
+    }
+}
+
+/// RFC 8030 message urgency, ordered from least to most urgent
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Urgency {
+    #[serde(rename = "very-low")]
+    VeryLow,
+    #[serde(rename = "low")]
+    Low,
+    #[serde(rename = "normal")]
+    Normal,
+    #[serde(rename = "high")]
+    High,
+}
+
 // Used for the server to flag a webpush client to deliver a Notification or Check storage
 pub enum ServerNotification {
     CheckStorage,
@@ -46,6 +92,10 @@ pub enum ClientMessage {
         version: String,
     },
 
+    /// Only notifications at least this urgent are to be pushed for now, the
+    /// rest waits in storage until the threshold is lowered again
+    Urgency { min: Urgency },
+
 }
 
 #[derive(Deserialize)]
@@ -79,9 +129,83 @@ pub enum ServerMessage {
         status: u32,
     },
 
+    Urgency { status: u32 },
+
+    Error { reason: String, status: u32 },
+
     Notification(Notification),
 }
 
+impl ServerMessage {
+    /// Wraps `notif` to be sent to its client, leaving out what's only for
+    /// the server's own bookkeeping
+    pub fn notification(mut notif: Notification) -> ServerMessage {
+        notif.receipt = None;
+        ServerMessage::Notification(notif)
+    }
+
+    /// Serializes this message for `Encoding::MessagePack`
+    pub fn to_msgpack(&self) -> Result<Vec<u8>> {
+        let ret = match *self {
+            ServerMessage::Notification(ref n) => {
+                let data = match n.data {
+                    Some(ref data) => Some(Bytes(
+                        base64::decode_config(data, base64::URL_SAFE_NO_PAD)
+                            .chain_err(|| "invalid base64url notification data")?,
+                    )),
+                    None => None,
+                };
+                rmp_serde::to_vec_named(&BinaryNotification {
+                    message_type: "notification",
+                    uaid: &n.uaid,
+                    channel_id: &n.channel_id,
+                    version: &n.version,
+                    ttl: n.ttl,
+                    topic: n.topic.as_ref(),
+                    timestamp: n.timestamp,
+                    data: data,
+                    headers: n.headers.as_ref(),
+                    urgency: n.urgency,
+                    sortkey_timestamp: n.sortkey_timestamp,
+                })
+            }
+            ref msg => rmp_serde::to_vec_named(msg),
+        };
+        ret.chain_err(|| "failed to serialize msgpack")
+    }
+}
+
+// `ServerMessage::Notification` as laid out for MessagePack
+#[derive(Serialize)]
+struct BinaryNotification<'a> {
+    #[serde(rename = "messageType")]
+    message_type: &'static str,
+    uaid: &'a Option<String>,
+    #[serde(rename = "channelID")]
+    channel_id: &'a Uuid,
+    version: &'a str,
+    ttl: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    topic: Option<&'a String>,
+    timestamp: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Bytes>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    headers: Option<&'a HashMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    urgency: Option<Urgency>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sortkey_timestamp: Option<u64>,
+}
+
+struct Bytes(Vec<u8>);
+
+impl Serialize for Bytes {
+    fn serialize<S: Serializer>(&self, serializer: S) -> ::std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&self.0)
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Notification {
     pub uaid: Option<String>,
@@ -96,4 +220,21 @@ pub struct Notification {
     pub data: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     headers: Option<HashMap<String, String>>,
+    /// Urgency given by the app server, `Normal` if none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub urgency: Option<Urgency>,
+    /// Sort key timestamp of stored notifications, needed to delete them
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sortkey_timestamp: Option<u64>,
+    /// Where the delivery receipt goes once this is acked. Stored along with
+    /// the notification, but never sent to clients (see
+    /// `ServerMessage::notification`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub receipt: Option<String>,
+}
+
+impl Notification {
+    pub fn urgency(&self) -> Urgency {
+        self.urgency.unwrap_or(Urgency::Normal)
+    }
 }
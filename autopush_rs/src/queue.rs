@@ -4,20 +4,55 @@
 //! the receiving side, and then the sending side is done by the Rust thread
 //! pushing requests over to Python. A `Sender` here is saved off in the
 //! `Server` for sending messages.
+//!
+//! Calls are queued up in one of two lanes by their `Priority`. Interactive
+//! calls, which clients are waiting on, are handed out ahead of background
+//! ones. So background calls aren't starved entirely, one of them is handed
+//! out after every `MAX_INTERACTIVE_STREAK` interactive calls taken while
+//! background ones were waiting.
+//!
+//! The queue may be given a capacity, the number of calls waiting for a
+//! worker each lane holds at most. Lanes fill up independently, so a backlog
+//! of background calls never keeps interactive ones out. Sending to a full
+//! lane fails right away rather than blocking the Rust thread, leaving it to
+//! shed load until Python has caught up.
 
-use std::sync::mpsc;
-use std::sync::Mutex;
+use std::collections::VecDeque;
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
-use call::{AutopushPythonCall, PythonCall};
+use call::{AutopushPythonCall, Priority, PythonCall};
+use errors::*;
 use rt::{self, AutopushError};
 
+const MAX_INTERACTIVE_STREAK: u32 = 8;
+
 #[repr(C)]
 pub struct AutopushQueue {
-    tx: Mutex<Sender>,
-    rx: Mutex<Option<mpsc::Receiver<Option<PythonCall>>>>,
+    shared: Arc<Shared>,
+}
+
+#[derive(Clone)]
+pub struct Sender {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    // Signalled whenever a call is queued up, or the queue is closed
+    ready: Condvar,
+    // Per lane
+    capacity: Option<usize>,
 }
 
-pub type Sender = mpsc::Sender<Option<PythonCall>>;
+struct State {
+    // Waiting calls by `Priority`, oldest first
+    lanes: [VecDeque<PythonCall>; 2],
+    // Interactive calls handed out in a row while background ones waited
+    interactive_streak: u32,
+    closed: bool,
+}
 
 fn _assert_kinds() {
     fn _assert<T: Send + Sync>() {}
@@ -25,13 +60,21 @@ fn _assert_kinds() {
 }
 
 #[no_mangle]
-pub extern "C" fn autopush_queue_new(err: &mut AutopushError) -> *mut AutopushQueue {
+pub extern "C" fn autopush_queue_new(
+    capacity: usize,
+    err: &mut AutopushError,
+) -> *mut AutopushQueue {
     rt::catch(err, || {
-        let (tx, rx) = mpsc::channel();
-
         Box::new(AutopushQueue {
-            tx: Mutex::new(tx),
-            rx: Mutex::new(Some(rx)),
+            shared: Arc::new(Shared {
+                state: Mutex::new(State {
+                    lanes: [VecDeque::new(), VecDeque::new()],
+                    interactive_streak: 0,
+                    closed: false,
+                }),
+                ready: Condvar::new(),
+                capacity: if capacity == 0 { None } else { Some(capacity) },
+            }),
         })
     })
 }
@@ -40,30 +83,73 @@ pub extern "C" fn autopush_queue_new(err: &mut AutopushError) -> *mut AutopushQu
 pub extern "C" fn autopush_queue_recv(
     queue: *mut AutopushQueue,
     err: &mut AutopushError,
+) -> *mut AutopushPythonCall {
+    rt::catch(err, || unsafe { (*queue).recv(Wait::Forever).map(Box::new) })
+}
+
+/// Like `autopush_queue_recv`, except without waiting for a call if none is
+/// queued up.
+#[no_mangle]
+pub extern "C" fn autopush_queue_try_recv(
+    queue: *mut AutopushQueue,
+    err: &mut AutopushError,
+) -> *mut AutopushPythonCall {
+    rt::catch(err, || unsafe { (*queue).recv(Wait::Never).map(Box::new) })
+}
+
+/// Like `autopush_queue_recv`, except waiting at most `timeout_ms` for a call.
+#[no_mangle]
+pub extern "C" fn autopush_queue_recv_timeout(
+    queue: *mut AutopushQueue,
+    timeout_ms: u64,
+    err: &mut AutopushError,
 ) -> *mut AutopushPythonCall {
     rt::catch(err, || unsafe {
-        let mut rx = (*queue).rx.lock().unwrap();
-        let msg = match *rx {
-            // this can't panic because we hold a reference to at least one
-            // sender, so it'll always block waiting for the next message
-            Some(ref rx) => rx.recv().unwrap(),
-
-            // the "done" message was received by someone else, so we just keep
-            // propagating that
-            None => return None,
-        };
-        match msg {
-            Some(msg) => Some(Box::new(AutopushPythonCall::new(msg))),
+        (*queue)
+            .recv(Wait::For(Duration::from_millis(timeout_ms)))
+            .map(Box::new)
+    })
+}
 
-            // the senders are done, so all future calls shoudl bail out
-            None => {
-                *rx = None;
-                None
+/// Receives up to `max` calls into `calls`, returning how many.
+///
+/// Waits at most `timeout_ms` for the first call, then takes whatever else is
+/// already queued up without waiting any further.
+#[no_mangle]
+pub extern "C" fn autopush_queue_recv_batch(
+    queue: *mut AutopushQueue,
+    calls: *mut *mut AutopushPythonCall,
+    max: usize,
+    timeout_ms: u64,
+    err: &mut AutopushError,
+) -> usize {
+    // The caller owns `calls`, nothing to unwind there
+    let calls = AssertUnwindSafe(calls);
+    rt::catch(err, move || unsafe {
+        let queue = &*queue;
+        let mut wait = Wait::For(Duration::from_millis(timeout_ms));
+        let mut n = 0;
+        while n < max {
+            match queue.recv(wait) {
+                Some(call) => *calls.offset(n as isize) = Box::into_raw(Box::new(call)),
+                None => break,
             }
+            n += 1;
+            wait = Wait::Never;
         }
+        n
     })
 }
 
+/// Whether the queue is done, no more calls coming out of it.
+#[no_mangle]
+pub extern "C" fn autopush_queue_closed(
+    queue: *mut AutopushQueue,
+    err: &mut AutopushError,
+) -> usize {
+    rt::catch(err, || unsafe { (*queue).is_closed() as usize })
+}
+
 #[no_mangle]
 pub extern "C" fn autopush_queue_free(queue: *mut AutopushQueue) {
     rt::abort_on_panic(|| unsafe {
@@ -71,8 +157,117 @@ pub extern "C" fn autopush_queue_free(queue: *mut AutopushQueue) {
     })
 }
 
+#[derive(Clone, Copy)]
+enum Wait {
+    Forever,
+    For(Duration),
+    Never,
+}
+
 impl AutopushQueue {
     pub fn tx(&self) -> Sender {
-        self.tx.lock().unwrap().clone()
+        Sender {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Takes the next call off the queue, waiting for one as long as `wait`
+    /// says. `None` if there wasn't one in time, or there won't be any more.
+    fn recv(&self, wait: Wait) -> Option<AutopushPythonCall> {
+        let deadline = match wait {
+            Wait::For(dur) => Some(Instant::now() + dur),
+            _ => None,
+        };
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            if let Some(call) = state.pop() {
+                return Some(AutopushPythonCall::new(call));
+            }
+            // the senders are done, so all future calls should bail out
+            if state.closed {
+                return None;
+            }
+            state = match wait {
+                Wait::Forever => self.shared.ready.wait(state).unwrap(),
+                Wait::For(_) => {
+                    let now = Instant::now();
+                    let deadline = deadline.unwrap();
+                    if now >= deadline {
+                        return None;
+                    }
+                    self.shared.ready.wait_timeout(state, deadline - now).unwrap().0
+                }
+                Wait::Never => return None,
+            };
+        }
+    }
+
+    fn is_closed(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        state.closed && state.lanes.iter().all(|lane| lane.is_empty())
+    }
+}
+
+impl State {
+    fn pop(&mut self) -> Option<PythonCall> {
+        let interactive = Priority::Interactive as usize;
+        let background = Priority::Background as usize;
+        let background_waiting = !self.lanes[background].is_empty();
+        let lane = if self.lanes[interactive].is_empty() ||
+            (background_waiting && self.interactive_streak >= MAX_INTERACTIVE_STREAK)
+        {
+            background
+        } else {
+            interactive
+        };
+        let call = self.lanes[lane].pop_front();
+        if call.is_some() && lane == interactive && background_waiting {
+            self.interactive_streak += 1;
+        } else {
+            self.interactive_streak = 0;
+        }
+        call
+    }
+}
+
+impl Sender {
+    /// Queues up `call` for a Python worker, failing with `PythonQueueFull`
+    /// if its lane is at capacity.
+    pub fn send(&self, call: PythonCall) -> Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        let priority = call.priority();
+        if self.shared.saturated(&state, priority) {
+            return Err(ErrorKind::PythonQueueFull.into());
+        }
+        state.lanes[priority as usize].push_back(call);
+        self.shared.ready.notify_one();
+        Ok(())
+    }
+
+    /// Lets the workers know no more calls are coming.
+    pub fn close(&self) {
+        self.shared.state.lock().unwrap().closed = true;
+        self.shared.ready.notify_all();
+    }
+
+    /// Number of calls of `priority` waiting for a worker
+    pub fn depth(&self, priority: Priority) -> usize {
+        self.shared.state.lock().unwrap().lanes[priority as usize].len()
+    }
+
+    /// Whether interactive calls, like those new clients make, are being
+    /// turned away.
+    pub fn is_saturated(&self) -> bool {
+        let state = self.shared.state.lock().unwrap();
+        self.shared.saturated(&state, Priority::Interactive)
+    }
+}
+
+impl Shared {
+    fn saturated(&self, state: &State, priority: Priority) -> bool {
+        match self.capacity {
+            Some(capacity) => state.lanes[priority as usize].len() >= capacity,
+            None => false,
+        }
     }
 }
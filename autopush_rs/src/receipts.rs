@@ -0,0 +1,280 @@
+//! Delivery receipts for acknowledged notifications
+//!
+//! Once a client acks a notification its sender gets told about it. App
+//! servers wanting to know supply a receipt URL with the push (the
+//! `Push-Receipt` header, validated by the endpoint), which is POSTed a JSON
+//! `Receipt`. Each attempt gets `ATTEMPT_TIMEOUT` to complete, and failed
+//! deliveries are retried with exponential backoff, up to `MAX_ATTEMPTS`
+//! times in total. At most `MAX_IN_FLIGHT` deliveries are under way at once,
+//! receipts beyond that being dropped and counted as such.
+//!
+//! Receipt URLs come from whoever sent the push, so they're only ever POSTed
+//! to over https, and only once the host has resolved to public addresses:
+//! loopback, private, link-local and the like are refused, keeping senders
+//! from pointing us at our own network.
+//!
+//! Notifications without a receipt URL may instead have their receipts handed
+//! to Python, which is the local receipts queue, when `local_receipts` is
+//! enabled.
+
+use std::cell::Cell;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, ToSocketAddrs};
+use std::rc::Rc;
+use std::time::Duration;
+
+use cadence::prelude::*;
+use futures::future::{self, Either, Loop};
+use futures::Future;
+use futures_cpupool::CpuPool;
+use hyper::header::ContentType;
+use hyper::{self, Method, Uri};
+use hyper_tls::HttpsConnector;
+use native_tls::TlsConnector;
+use serde_json;
+use time;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_service::Service;
+use uuid::Uuid;
+
+use errors::*;
+use protocol::Notification;
+use server::Server;
+
+const MAX_ATTEMPTS: u32 = 5;
+const FIRST_RETRY: u64 = 1;
+// Seconds a single attempt has to get a response
+const ATTEMPT_TIMEOUT: u64 = 10;
+const MAX_IN_FLIGHT: usize = 1000;
+
+#[derive(Serialize)]
+pub struct Receipt {
+    pub message_id: String,
+    #[serde(rename = "channelID")]
+    pub channel_id: Uuid,
+    /// Seconds since the epoch the client acked the notification at
+    pub delivered_at: i64,
+}
+
+/// The HTTPS client receipts are POSTed with
+pub struct ReceiptClient {
+    // Shared by all deliveries, the connector can't be cloned
+    client: Rc<hyper::Client<HttpsConnector<PublicConnector>>>,
+    // Deliveries currently under way, retries included
+    in_flight: Rc<Cell<usize>>,
+}
+
+impl ReceiptClient {
+    pub fn new(handle: &Handle) -> Result<ReceiptClient> {
+        let tls = TlsConnector::builder()
+            .and_then(|builder| builder.build())
+            .chain_err(|| "failed to create tls connector")?;
+        let connector = PublicConnector {
+            dns: CpuPool::new(1),
+            handle: handle.clone(),
+        };
+        Ok(ReceiptClient {
+            client: Rc::new(
+                hyper::Client::configure()
+                    .connector(HttpsConnector::from((connector, tls)))
+                    .build(handle),
+            ),
+            in_flight: Rc::new(Cell::new(0)),
+        })
+    }
+}
+
+/// Opens connections for receipts, but only to public addresses
+///
+/// The host is resolved off the reactor, like hyper's own connector does, and
+/// refused outright if any address it resolves to isn't public. Connecting to
+/// the address that was checked, rather than resolving again, means DNS can't
+/// be changed underneath us in between.
+struct PublicConnector {
+    dns: CpuPool,
+    handle: Handle,
+}
+
+impl Service for PublicConnector {
+    type Request = Uri;
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = Box<Future<Item = TcpStream, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        if uri.scheme() != Some("https") {
+            return Box::new(future::err(refused("receipt url isn't https")));
+        }
+        let host = match uri.host() {
+            // IPv6 literals come bracketed, which `to_socket_addrs` won't take
+            Some(host) => host.trim_left_matches('[').trim_right_matches(']').to_string(),
+            None => return Box::new(future::err(refused("receipt url has no host"))),
+        };
+        let port = uri.port().unwrap_or(443);
+        let resolved = self.dns.spawn_fn(move || {
+            (host.as_str(), port)
+                .to_socket_addrs()
+                .map(|addrs| addrs.collect::<Vec<_>>())
+        });
+        let handle = self.handle.clone();
+        Box::new(resolved.and_then(move |addrs| -> Self::Future {
+            let addr = match addrs.first() {
+                Some(addr) if addrs.iter().all(|a| is_public(&a.ip())) => *addr,
+                Some(_) => return Box::new(future::err(refused("receipt host isn't public"))),
+                None => return Box::new(future::err(refused("receipt host didn't resolve"))),
+            };
+            Box::new(TcpStream::connect(&addr, &handle))
+        }))
+    }
+}
+
+fn refused(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, msg)
+}
+
+/// Whether `ip` is somewhere receipts may be sent
+///
+/// Anything that only makes sense on our side of the network is out:
+/// unspecified, loopback, private, shared (carrier-grade NAT), link-local,
+/// multicast, broadcast and reserved addresses, along with their IPv6
+/// equivalents and IPv4 addresses embedded in IPv6 ones.
+fn is_public(ip: &IpAddr) -> bool {
+    match *ip {
+        IpAddr::V4(ref ip) => is_public_v4(ip),
+        IpAddr::V6(ref ip) => is_public_v6(ip),
+    }
+}
+
+fn is_public_v4(ip: &Ipv4Addr) -> bool {
+    let octets = ip.octets();
+    !(octets[0] == 0 || ip.is_loopback() || ip.is_private() || ip.is_link_local()
+        || (octets[0] == 100 && octets[1] & 0xc0 == 64) || octets[0] >= 224)
+}
+
+fn is_public_v6(ip: &Ipv6Addr) -> bool {
+    let segments = ip.segments();
+    // IPv4-mapped (::ffff:0:0/96) and IPv4-compatible (::/96), which also
+    // covers the unspecified and loopback addresses
+    if segments[..5].iter().all(|s| *s == 0) && (segments[5] == 0 || segments[5] == 0xffff) {
+        let v4 = Ipv4Addr::new(
+            (segments[6] >> 8) as u8,
+            segments[6] as u8,
+            (segments[7] >> 8) as u8,
+            segments[7] as u8,
+        );
+        return is_public_v4(&v4);
+    }
+    // Unique local (fc00::/7), link-local (fe80::/10) and multicast (ff00::/8)
+    !(segments[0] & 0xfe00 == 0xfc00 || segments[0] & 0xffc0 == 0xfe80
+        || segments[0] & 0xff00 == 0xff00)
+}
+
+impl Server {
+    /// Lets the sender of `notif` know it was just acked by `uaid`.
+    ///
+    /// Delivery happens in the background, there's nothing to wait for.
+    pub fn send_receipt(&self, uaid: &Uuid, notif: &Notification) {
+        let receipt = Receipt {
+            message_id: notif.version.clone(),
+            channel_id: notif.channel_id,
+            delivered_at: time::get_time().sec,
+        };
+        match notif.receipt {
+            Some(ref url) => {
+                let url = match url.parse::<Uri>() {
+                    Ok(ref url) if url.scheme() != Some("https") => {
+                        debug!("Refusing non-https receipt url {:?}", url);
+                        return;
+                    }
+                    Ok(url) => url,
+                    Err(e) => {
+                        debug!("Invalid receipt url {:?}: {}", url, e);
+                        return;
+                    }
+                };
+                let in_flight = self.receipts.in_flight.clone();
+                if in_flight.get() >= MAX_IN_FLIGHT {
+                    debug!("Too many receipts in flight, dropping one");
+                    self.metrics
+                        .incr_with_tags("ua.receipt")
+                        .with_tag("outcome", "dropped")
+                        .send()
+                        .ok();
+                    return;
+                }
+                in_flight.set(in_flight.get() + 1);
+                let body = serde_json::to_string(&receipt).expect("failed to serialize receipt");
+                let metrics = self.metrics.clone();
+                let post = self.post_receipt(url, body).then(move |res| {
+                    in_flight.set(in_flight.get() - 1);
+                    let outcome = match res {
+                        Ok(()) => "delivered",
+                        Err(e) => {
+                            debug!("Failed to deliver receipt: {}", e);
+                            "failed"
+                        }
+                    };
+                    metrics
+                        .incr_with_tags("ua.receipt")
+                        .with_tag("outcome", outcome)
+                        .send()
+                        .ok();
+                    Ok(())
+                });
+                self.handle.spawn(post);
+            }
+            None if self.opts.local_receipts => {
+                let call = self.store_receipt(uaid.simple().to_string(), receipt);
+                self.handle.spawn(call.then(|res| {
+                    if let Err(e) = res {
+                        debug!("Failed to store receipt: {}", e);
+                    }
+                    Ok(())
+                }));
+            }
+            None => {}
+        }
+    }
+
+    fn post_receipt(&self, url: Uri, body: String) -> MyFuture<()> {
+        let client = self.receipts.client.clone();
+        let handle = self.handle.clone();
+        let attempts = future::loop_fn(0, move |attempt| -> MyFuture<Loop<(), u32>> {
+            let mut req = hyper::Request::new(Method::Post, url.clone());
+            req.headers_mut().set(ContentType::json());
+            req.set_body(body.clone());
+            let timeout = match Timeout::new(Duration::from_secs(ATTEMPT_TIMEOUT), &handle) {
+                Ok(timeout) => timeout,
+                Err(e) => return Box::new(future::err(e.into())),
+            };
+            let handle = handle.clone();
+            let request = client.request(req).select2(timeout);
+            Box::new(request.then(move |res| -> MyFuture<Loop<(), u32>> {
+                let error = match res {
+                    Ok(Either::A((ref resp, _))) if resp.status().is_success() => {
+                        return Box::new(future::ok(Loop::Break(())))
+                    }
+                    Ok(Either::A((resp, _))) => format!("receipt url responded {}", resp.status()),
+                    Err(Either::A((e, _))) => e.to_string(),
+                    Ok(Either::B(((), _))) => "receipt url timed out".to_string(),
+                    Err(Either::B((e, _))) => e.to_string(),
+                };
+                if attempt + 1 >= MAX_ATTEMPTS {
+                    return Box::new(future::err(error.into()));
+                }
+                debug!("Retrying receipt delivery: {}", error);
+                let delay = Duration::from_secs(FIRST_RETRY << attempt);
+                match Timeout::new(delay, &handle) {
+                    Ok(timeout) => Box::new(
+                        timeout
+                            .map(move |()| Loop::Continue(attempt + 1))
+                            .chain_err(|| "receipt retry timer failed"),
+                    ),
+                    Err(e) => Box::new(future::err(e.into())),
+                }
+            }))
+        });
+        Box::new(attempts)
+    }
+}
@@ -35,7 +35,7 @@ use std::panic;
 use std::ptr;
 use std::mem;
 use std::any::Any;
-use std::cell::Cell;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 /// Generic error which is used on all function calls from Python into Rust.
 ///
@@ -113,15 +113,18 @@ pub unsafe extern "C" fn autopush_error_cleanup(err: *mut AutopushError) {
 
 /// Helper structure to provide "unwind safety" to ensure we don't reuse values
 /// accidentally after a panic.
+///
+/// The guard itself is safe to share across threads, so it's `Sync` whenever
+/// the state it protects is.
 pub struct UnwindGuard<T> {
-    poisoned: Cell<bool>,
+    poisoned: AtomicBool,
     inner: T,
 }
 
 impl<T> UnwindGuard<T> {
     pub fn new(t: T) -> UnwindGuard<T> {
         UnwindGuard {
-            poisoned: Cell::new(false),
+            poisoned: AtomicBool::new(false),
             inner: t,
         }
     }
@@ -146,7 +149,7 @@ impl<T> UnwindGuard<T> {
         R: AbiInto,
     {
         err.assert_empty();
-        if self.poisoned.get() {
+        if self.poisoned.load(Ordering::SeqCst) {
             err.fill(Box::new(String::from("accessing poisoned object")));
             return R::null();
         }
@@ -164,7 +167,7 @@ impl<T> UnwindGuard<T> {
             }),
         );
         if panicked {
-            self.poisoned.set(true);
+            self.poisoned.store(true, Ordering::SeqCst);
         }
         return ret;
     }
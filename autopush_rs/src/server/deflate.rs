@@ -0,0 +1,593 @@
+//! permessage-deflate websocket compression (RFC 7692)
+//!
+//! Tungstenite 0.5 has no support for websocket extensions, and refuses any
+//! frame with an RSV bit set, so compression happens underneath it instead.
+//! `negotiate` picks the parameters for a client's `Sec-WebSocket-Extensions`
+//! offers while `Dispatch` classifies the request, and the `Codec` for them
+//! then rewrites frames in `WebpushIo` as they pass between the socket and
+//! tungstenite:
+//!
+//! * Compressed messages from the client (RSV1 set on their first frame) are
+//!   inflated once all their frames are in, and handed on as a single frame.
+//!   Control frames and uncompressed messages are handed on as they are.
+//! * Every message tungstenite writes is deflated and sent as a single frame
+//!   with RSV1 set, control frames are sent as they are.
+//!
+//! Compression contexts are the bulk of a connection's memory, zlib taking
+//! about 256KiB to compress with a 15 bit window and 40KiB to decompress.
+//! Without context takeover (the default) they're only allocated while a
+//! message is being compressed or decompressed, so idle connections don't
+//! hold on to any. Otherwise they live as long as the connection does, and
+//! `DeflateOptions::memory` caps how large their windows are negotiated to.
+
+use std::cmp;
+use std::io;
+use std::mem;
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+
+use server::webpush_io::{Frame, FrameCursor, Piece};
+
+/// Name of the extension, as offered in `Sec-WebSocket-Extensions`
+const EXTENSION: &str = "permessage-deflate";
+
+// Ends every message flushed with Z_SYNC_FLUSH, left out on the wire
+const TAIL: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+// Smallest window zlib compresses raw deflate data with, and the largest
+// anyone may use
+const MIN_WINDOW_BITS: u8 = 9;
+const MAX_WINDOW_BITS: u8 = 15;
+
+// Growth of buffers being compressed or decompressed into
+const CHUNK: usize = 4096;
+
+/// How permessage-deflate is offered to clients
+pub struct DeflateOptions {
+    /// Largest LZ77 window, as a base 2 logarithm between 9 and 15
+    pub window_bits: u8,
+    /// Whether compression contexts are kept from one message to the next
+    pub context_takeover: bool,
+    /// Most bytes the compression contexts of a connection may take up,
+    /// `None` for no limit
+    pub memory: Option<usize>,
+}
+
+/// The parameters a connection agreed on
+#[derive(Clone, Debug, PartialEq)]
+pub struct Params {
+    /// Window we compress messages with
+    server_window_bits: u8,
+    /// Window the client compresses messages with, if it offered to be told
+    client_window_bits: Option<u8>,
+    server_takeover: bool,
+    client_takeover: bool,
+}
+
+/// Picks the first of the `permessage-deflate` offers in `Sec-WebSocket-
+/// Extensions` header `values` that we can accept, if any.
+pub fn negotiate<'a, I>(opts: &DeflateOptions, values: I) -> Option<Params>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    values
+        .into_iter()
+        .flat_map(|value| value.split(','))
+        .filter_map(|offer| {
+            let mut params = offer.split(';').map(str::trim);
+            match params.next() {
+                Some(EXTENSION) => accept(opts, params),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+fn accept<'a, I>(opts: &DeflateOptions, params: I) -> Option<Params>
+where
+    I: Iterator<Item = &'a str>,
+{
+    let mut server_bits = opts.window_bits;
+    let mut client_bits = None;
+    let mut server_takeover = opts.context_takeover;
+    let mut client_takeover = opts.context_takeover;
+    let mut seen = Vec::new();
+    for param in params.filter(|p| !p.is_empty()) {
+        let mut parts = param.splitn(2, '=');
+        let name = parts.next().unwrap().trim();
+        let value = parts.next().map(|v| v.trim().trim_matches('"'));
+        // Offers repeating a parameter are invalid
+        if seen.contains(&name) {
+            return None;
+        }
+        seen.push(name);
+        match (name, value) {
+            ("server_no_context_takeover", None) => server_takeover = false,
+            ("client_no_context_takeover", None) => client_takeover = false,
+            ("server_max_window_bits", Some(bits)) => {
+                server_bits = cmp::min(server_bits, window_bits(bits)?);
+            }
+            ("client_max_window_bits", None) => client_bits = Some(opts.window_bits),
+            ("client_max_window_bits", Some(bits)) => {
+                client_bits = Some(cmp::min(opts.window_bits, window_bits(bits)?));
+            }
+            _ => return None,
+        }
+    }
+    // zlib can't compress within a window of 256 bytes
+    if server_bits < MIN_WINDOW_BITS {
+        return None;
+    }
+
+    // Shrink windows to the memory budget, ours before the client's (which
+    // it only lets us pick if it offered `client_max_window_bits`)
+    if let Some(budget) = opts.memory {
+        loop {
+            let client = client_bits.unwrap_or(MAX_WINDOW_BITS);
+            if memory(server_bits, client) <= budget {
+                break;
+            }
+            match client_bits {
+                _ if server_bits > MIN_WINDOW_BITS => server_bits -= 1,
+                Some(bits) if bits > MIN_WINDOW_BITS => client_bits = Some(bits - 1),
+                _ => return None,
+            }
+        }
+    }
+
+    Some(Params {
+        server_window_bits: server_bits,
+        client_window_bits: client_bits,
+        server_takeover: server_takeover,
+        client_takeover: client_takeover,
+    })
+}
+
+// A window size in an offer, which must be within 8 and 15
+fn window_bits(value: &str) -> Option<u8> {
+    match value.parse() {
+        Ok(bits) if bits >= 8 && bits <= MAX_WINDOW_BITS => Some(bits),
+        _ => None,
+    }
+}
+
+// zlib's own estimate of what compressing (with its default `memLevel` of 8)
+// and decompressing with the given windows takes up
+fn memory(server_bits: u8, client_bits: u8) -> usize {
+    (1 << (server_bits + 2)) + (1 << (8 + 9)) + (1 << client_bits) + 7 * 1024
+}
+
+impl Params {
+    /// The `Sec-WebSocket-Extensions` value answering the client with these
+    pub fn header(&self) -> String {
+        let mut header = EXTENSION.to_string();
+        if !self.server_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if !self.client_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        if self.server_window_bits < MAX_WINDOW_BITS {
+            header.push_str(&format!(
+                "; server_max_window_bits={}",
+                self.server_window_bits
+            ));
+        }
+        if let Some(bits) = self.client_window_bits {
+            header.push_str(&format!("; client_max_window_bits={}", bits));
+        }
+        header
+    }
+}
+
+/// Compresses and decompresses the messages of a connection, see the module
+/// docs
+pub struct Codec {
+    params: Params,
+    compress: Option<Compress>,
+    decompress: Option<Decompress>,
+    incoming: FrameCursor,
+    // Header of the incoming frame being read, and how far into its payload
+    in_frame: Option<Frame>,
+    in_offset: u64,
+    // Opcode and (unmasked) payload of a compressed incoming message
+    in_message: Option<(u8, Vec<u8>)>,
+    outgoing: FrameCursor,
+    out_frame: Option<Frame>,
+    out_payload: Vec<u8>,
+    // Opcode and payload of the outgoing message being written
+    out_message: Option<(u8, Vec<u8>)>,
+    saved_in: i64,
+    saved_out: i64,
+}
+
+impl Codec {
+    pub fn new(params: Params) -> Codec {
+        Codec {
+            params: params,
+            compress: None,
+            decompress: None,
+            incoming: FrameCursor::new(),
+            in_frame: None,
+            in_offset: 0,
+            in_message: None,
+            outgoing: FrameCursor::new(),
+            out_frame: None,
+            out_payload: Vec::new(),
+            out_message: None,
+            saved_in: 0,
+            saved_out: 0,
+        }
+    }
+
+    /// Rewrites frames read from the client into `out`, for tungstenite to
+    /// read. Returns false once a message inflates past `max_message` bytes.
+    pub fn incoming(
+        &mut self,
+        mut data: &[u8],
+        out: &mut Vec<u8>,
+        max_message: u64,
+    ) -> io::Result<bool> {
+        while let Some(piece) = self.incoming.next(&mut data) {
+            match piece {
+                Piece::Header(frame) => {
+                    let compressed = frame.first & 0x40 != 0;
+                    let opcode = frame.first & 0x0f;
+                    match (opcode, self.in_message.is_some()) {
+                        // Control frames, and frames of uncompressed messages
+                        // (tungstenite makes sure they're valid)
+                        (op, _) if op & 0x08 != 0 => frame.write(out),
+                        (_, false) if !compressed => frame.write(out),
+                        // The first frame of a compressed message
+                        (0x1, false) | (0x2, false) => self.in_message = Some((opcode, Vec::new())),
+                        // Further frames of it
+                        (0x0, true) if !compressed => {}
+                        _ => return Err(invalid("invalid compressed websocket message")),
+                    }
+                    self.in_frame = Some(frame);
+                    self.in_offset = 0;
+                }
+                Piece::Payload(bytes) => {
+                    let frame = self.in_frame.unwrap();
+                    match self.in_message {
+                        Some((_, ref mut message)) if frame.first & 0x08 == 0 => {
+                            let mask = frame.mask.unwrap_or([0; 4]);
+                            let offset = self.in_offset;
+                            message.extend(
+                                bytes
+                                    .iter()
+                                    .enumerate()
+                                    .map(|(i, b)| b ^ mask[((offset + i as u64) % 4) as usize]),
+                            );
+                        }
+                        _ => out.extend_from_slice(bytes),
+                    }
+                    self.in_offset += bytes.len() as u64;
+                }
+            }
+
+            // Once the last frame of a compressed message is in, inflate it
+            let frame = self.in_frame.unwrap();
+            if !self.incoming.at_boundary() || frame.first & 0x88 != 0x80 {
+                continue;
+            }
+            let (opcode, mut message) = match self.in_message.take() {
+                Some(message) => message,
+                None => continue,
+            };
+            let compressed_len = message.len();
+            message.extend_from_slice(&TAIL);
+            let message = match self.decompress(&message, max_message)? {
+                Some(message) => message,
+                None => return Ok(false),
+            };
+            self.saved_in += message.len() as i64 - compressed_len as i64;
+            // Tungstenite insists on masked frames from clients, a mask of
+            // all zeroes leaves the payload as it is
+            Frame {
+                first: 0x80 | opcode,
+                len: message.len() as u64,
+                mask: Some([0; 4]),
+            }
+            .write(out);
+            out.extend_from_slice(&message);
+        }
+        Ok(true)
+    }
+
+    /// Rewrites frames tungstenite writes into `out`, each one once it's
+    /// complete.
+    pub fn outgoing(&mut self, mut data: &[u8], out: &mut Vec<u8>) -> io::Result<()> {
+        while let Some(piece) = self.outgoing.next(&mut data) {
+            match piece {
+                Piece::Header(frame) => self.out_frame = Some(frame),
+                Piece::Payload(bytes) => self.out_payload.extend_from_slice(bytes),
+            }
+            if !self.outgoing.at_boundary() {
+                continue;
+            }
+
+            let frame = self.out_frame.take().unwrap();
+            let payload = mem::replace(&mut self.out_payload, Vec::new());
+            let opcode = frame.first & 0x0f;
+            if opcode & 0x08 != 0 {
+                frame.write(out);
+                out.extend_from_slice(&payload);
+                continue;
+            }
+            match self.out_message {
+                Some((_, ref mut message)) => message.extend_from_slice(&payload),
+                None => self.out_message = Some((opcode, payload)),
+            }
+            if frame.first & 0x80 == 0 {
+                continue;
+            }
+
+            let (opcode, message) = self.out_message.take().unwrap();
+            let compressed = self.compress(&message)?;
+            // Without context takeover nothing else depends on what was
+            // compressed, so messages that didn't shrink can go out as they
+            // are
+            let (first, payload) =
+                if self.params.server_takeover || compressed.len() < message.len() {
+                    self.saved_out += message.len() as i64 - compressed.len() as i64;
+                    (0xc0 | opcode, compressed)
+                } else {
+                    (0x80 | opcode, message)
+                };
+            Frame {
+                first: first,
+                len: payload.len() as u64,
+                mask: None,
+            }
+            .write(out);
+            out.extend_from_slice(&payload);
+        }
+        Ok(())
+    }
+
+    /// Bytes compression saved since the last call, incoming and outgoing
+    pub fn take_saved(&mut self) -> (i64, i64) {
+        let saved = (self.saved_in, self.saved_out);
+        self.saved_in = 0;
+        self.saved_out = 0;
+        saved
+    }
+
+    fn compress(&mut self, mut data: &[u8]) -> io::Result<Vec<u8>> {
+        let bits = self.params.server_window_bits;
+        let mut out = Vec::with_capacity(data.len() / 2 + CHUNK);
+        {
+            let compress = self.compress.get_or_insert_with(|| {
+                Compress::new_with_window_bits(Compression::default(), false, bits)
+            });
+            loop {
+                if out.capacity() - out.len() < CHUNK {
+                    out.reserve(CHUNK);
+                }
+                let before = compress.total_in();
+                compress
+                    .compress_vec(data, &mut out, FlushCompress::Sync)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+                data = &data[(compress.total_in() - before) as usize..];
+                if data.is_empty() && out.len() < out.capacity() {
+                    break;
+                }
+            }
+        }
+        if !self.params.server_takeover {
+            self.compress = None;
+        }
+        if out.ends_with(&TAIL) {
+            let len = out.len() - TAIL.len();
+            out.truncate(len);
+        }
+        Ok(out)
+    }
+
+    // Inflates `data` (ending in `TAIL`), `None` if that's over `max` bytes
+    fn decompress(&mut self, mut data: &[u8], max: u64) -> io::Result<Option<Vec<u8>>> {
+        let bits = cmp::max(
+            self.params.client_window_bits.unwrap_or(MAX_WINDOW_BITS),
+            MIN_WINDOW_BITS,
+        );
+        let mut out = Vec::with_capacity(data.len() * 2 + CHUNK);
+        let result = {
+            let decompress = self
+                .decompress
+                .get_or_insert_with(|| Decompress::new_with_window_bits(false, bits));
+            loop {
+                if out.capacity() - out.len() < CHUNK {
+                    out.reserve(CHUNK);
+                }
+                let (before_in, before_out) = (decompress.total_in(), decompress.total_out());
+                let status = decompress
+                    .decompress_vec(data, &mut out, FlushDecompress::Sync)
+                    .map_err(|_| invalid("invalid compressed websocket message"))?;
+                data = &data[(decompress.total_in() - before_in) as usize..];
+                if out.len() as u64 > max {
+                    break None;
+                }
+                let stuck =
+                    decompress.total_in() == before_in && decompress.total_out() == before_out;
+                if status == Status::StreamEnd
+                    || stuck
+                    || (data.is_empty() && out.len() < out.capacity())
+                {
+                    break Some(out);
+                }
+            }
+        };
+        if !self.params.client_takeover {
+            self.decompress = None;
+        }
+        Ok(result)
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{negotiate, Codec, DeflateOptions, Params};
+
+    fn opts(window_bits: u8, context_takeover: bool, memory: Option<usize>) -> DeflateOptions {
+        DeflateOptions {
+            window_bits: window_bits,
+            context_takeover: context_takeover,
+            memory: memory,
+        }
+    }
+
+    fn params(server: u8, client: Option<u8>, takeover: bool) -> Params {
+        Params {
+            server_window_bits: server,
+            client_window_bits: client,
+            server_takeover: takeover,
+            client_takeover: takeover,
+        }
+    }
+
+    // A text message as a client sends it, masked
+    fn client_frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [0x12, 0x34, 0x56, 0x78];
+        let mut frame = vec![first, 0x80 | payload.len() as u8];
+        frame.extend_from_slice(&mask);
+        frame.extend(payload.iter().enumerate().map(|(i, b)| b ^ mask[i % 4]));
+        frame
+    }
+
+    #[test]
+    fn offers() {
+        let no_takeover = opts(15, false, None);
+        assert_eq!(
+            negotiate(&no_takeover, vec!["x-webkit-deflate-frame"]),
+            None
+        );
+        let accepted = negotiate(&no_takeover, vec!["permessage-deflate"]).unwrap();
+        assert_eq!(accepted, params(15, None, false));
+        assert_eq!(
+            accepted.header(),
+            "permessage-deflate; server_no_context_takeover; client_no_context_takeover"
+        );
+
+        // The first acceptable offer, over any number of headers
+        let takeover = opts(12, true, None);
+        let offers = vec![
+            "permessage-deflate; server_max_window_bits=8",
+            "permessage-deflate; client_max_window_bits; server_max_window_bits=\"10\", permessage-deflate",
+        ];
+        let accepted = negotiate(&takeover, offers).unwrap();
+        assert_eq!(accepted, params(10, Some(12), true));
+        assert_eq!(
+            accepted.header(),
+            "permessage-deflate; server_max_window_bits=10; client_max_window_bits=12"
+        );
+
+        let invalid = vec![
+            "permessage-deflate; server_max_window_bits",
+            "permessage-deflate; client_max_window_bits=16",
+            "permessage-deflate; server_no_context_takeover; server_no_context_takeover",
+            "permessage-deflate; unknown",
+        ];
+        for offer in invalid {
+            assert_eq!(negotiate(&takeover, vec![offer]), None, "{}", offer);
+        }
+    }
+
+    #[test]
+    fn memory_budget() {
+        // Our window shrinks first, then the client's if it lets us pick it
+        let budget = opts(15, true, Some(200 * 1024));
+        let accepted = negotiate(&budget, vec!["permessage-deflate"]).unwrap();
+        assert_eq!(accepted, params(13, None, true));
+        let budget = opts(15, true, Some(140 * 1024));
+        assert_eq!(negotiate(&budget, vec!["permessage-deflate"]), None);
+        let accepted =
+            negotiate(&budget, vec!["permessage-deflate; client_max_window_bits"]).unwrap();
+        assert_eq!(accepted, params(9, Some(11), true));
+    }
+
+    #[test]
+    fn round_trip() {
+        for &takeover in [false, true].iter() {
+            let mut server = Codec::new(params(15, None, takeover));
+            let mut client = Codec::new(params(15, None, takeover));
+            let text = "notification ".repeat(8);
+            let text = text.as_bytes();
+            for _ in 0..3 {
+                // What the server writes goes out compressed...
+                let mut wire = Vec::new();
+                let mut written = vec![0x81, 0x7e, 0x00, text.len() as u8];
+                written.extend_from_slice(text);
+                written.extend_from_slice(&[0x89, 0x00]);
+                for byte in written.iter() {
+                    server.outgoing(&[*byte], &mut wire).unwrap();
+                }
+                assert_eq!(wire[0], 0xc1);
+                assert!(wire.len() < written.len());
+                assert_eq!(&wire[wire.len() - 2..], &[0x89, 0x00]);
+
+                // ...and inflates again, as if it came from a client
+                let payload = wire[2..wire.len() - 2].to_vec();
+                let mut read = Vec::new();
+                let frame = client_frame(0xc1, &payload);
+                assert!(client.incoming(&frame, &mut read, 1024).unwrap());
+                assert_eq!(&read[..6], &[0x81, 0x80 | text.len() as u8, 0, 0, 0, 0]);
+                assert_eq!(&read[6..], text);
+            }
+            assert!(server.take_saved().1 > 0);
+            assert!(client.take_saved().0 > 0);
+            assert_eq!(server.take_saved(), (0, 0));
+        }
+    }
+
+    #[test]
+    fn fragments_and_control_frames() {
+        // With context takeover even messages that don't shrink are sent
+        // compressed
+        let mut server = Codec::new(params(15, None, true));
+        let mut wire = Vec::new();
+        server.outgoing(&[0x81, 0x05], &mut wire).unwrap();
+        server.outgoing(b"hello", &mut wire).unwrap();
+        let compressed = wire[2..].to_vec();
+
+        // A compressed message split over two frames with a ping in between
+        let mut data = client_frame(0x41, &compressed[..2]);
+        data.extend(client_frame(0x89, b"ping"));
+        data.extend(client_frame(0x80, &compressed[2..]));
+        // followed by an uncompressed one
+        data.extend(client_frame(0x81, b"plain"));
+        let mut read = Vec::new();
+        let mut client = Codec::new(params(15, None, false));
+        assert!(client.incoming(&data, &mut read, 1024).unwrap());
+        let mut expected = client_frame(0x89, b"ping");
+        expected.extend_from_slice(&[0x81, 0x85, 0, 0, 0, 0]);
+        expected.extend_from_slice(b"hello");
+        expected.extend(client_frame(0x81, b"plain"));
+        assert_eq!(read, expected);
+
+        // RSV1 is only valid on the first frame of a message
+        let mut data = client_frame(0x41, &compressed[..2]);
+        data.extend(client_frame(0xc0, &compressed[2..]));
+        let mut client = Codec::new(params(15, None, false));
+        assert!(client.incoming(&data, &mut Vec::new(), 1024).is_err());
+    }
+
+    #[test]
+    fn inflated_limit() {
+        let mut server = Codec::new(params(15, None, false));
+        let mut wire = Vec::new();
+        server
+            .outgoing(&[0x82, 0x7e, 0x04, 0x00], &mut wire)
+            .unwrap();
+        server.outgoing(&[0; 1024], &mut wire).unwrap();
+        let frame = client_frame(0xc2, &wire[2..]);
+        let mut client = Codec::new(params(15, None, false));
+        assert!(!client.incoming(&frame, &mut Vec::new(), 1023).unwrap());
+        let mut client = Codec::new(params(15, None, false));
+        assert!(client.incoming(&frame, &mut Vec::new(), 1024).unwrap());
+    }
+}
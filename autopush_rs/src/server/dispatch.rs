@@ -9,8 +9,20 @@
 //! socket to parse an initial HTTP request. This request will be parsed by the
 //! `httparse` crate. Once we've got a request we take a look at the headers and
 //! if we find a websocket upgrade we classify it as a websocket request. If
-//! it's otherwise a `/status` request, we return that we're supposed to get the
-//! status, and finally after all that if it doesn't match we return an error.
+//! it's otherwise a `/status` or `/metrics` request, we return that we're
+//! supposed to get the status or metrics. Requests for `/sse` are the event
+//! stream and message posts of the SSE fallback transport (see the `sse`
+//! module), for which the whole body is read here as well. Requests for
+//! `/subscribe`, `/subscription/` and `/message/` are for the RFC 8030
+//! interface of the `push_service` module, when it's enabled. Finally after all that if it
+//! doesn't match we return an error.
+//!
+//! Websocket requests are also vetted here, as tungstenite can only drop a
+//! connection it doesn't like rather than respond with a status code. Requests
+//! for an unsupported `Sec-WebSocket-Version` or `Sec-WebSocket-Protocol`, or
+//! from an `Origin` outside the configured allow-list, are classified as
+//! `RequestType::Rejected` so a proper 4xx can be sent back. Any
+//! permessage-deflate compression they offer is negotiated here as well.
 //!
 //! This is basically a "poor man's" HTTP router and while it should be good
 //! enough for now it should probably be extended/refactored in the future!
@@ -21,6 +33,10 @@
 //! tungstenite library, which'll duplicate header parsing but we don't have
 //! many other options for now!
 
+use std::borrow::Cow;
+use std::cmp;
+use std::rc::Rc;
+
 use bytes::BytesMut;
 use futures::{Future, Poll};
 use httparse;
@@ -28,24 +44,76 @@ use tokio_core::net::TcpStream;
 use tokio_io::AsyncRead;
 
 use errors::*;
+use server::{Server, ServerOptions};
+use server::deflate::{self, Params};
+use server::push_service::PushRequest;
 use server::webpush_io::WebpushIo;
 use server::tls::MaybeTlsStream;
 
+// Largest `POST /sse/<token>` body read in, whatever `max_message_size` is:
+// the whole body is buffered before it's handed over
+const MAX_SSE_POST: u64 = 256 * 1024;
+
 pub struct Dispatch {
     socket: Option<MaybeTlsStream<TcpStream>>,
     data: BytesMut,
+    // How much of `data` we need before we're done, if known
+    want: usize,
+    srv: Rc<Server>,
 }
 
 pub enum RequestType {
-    Websocket,
+    /// A websocket upgrade, with the subprotocol selected for it (if the
+    /// client asked for any) and the extensions agreed on
+    Websocket(Option<String>, Option<String>),
+    Rejected(Rejection),
     Status,
+    Metrics,
+    /// An SSE event stream, with the client's user agent
+    SseStream(String),
+    /// A message posted to the SSE session with the given token, with its
+    /// body unless that was too large
+    SsePost(String, Option<Vec<u8>>),
+    /// A request for one of the RFC 8030 resources
+    PushService(PushRequest),
+}
+
+/// Why a websocket upgrade request was refused
+#[derive(Clone, Copy, Debug)]
+pub enum Rejection {
+    /// Anything but version 13 of the websocket protocol
+    UnsupportedVersion,
+    /// None of the subprotocols offered by the client are supported
+    UnsupportedProtocol,
+    /// The `Origin` isn't in the allow-list
+    ForbiddenOrigin,
+}
+
+impl Rejection {
+    pub fn status(&self) -> &'static str {
+        match *self {
+            Rejection::UnsupportedVersion => "426 Upgrade Required",
+            Rejection::UnsupportedProtocol => "400 Bad Request",
+            Rejection::ForbiddenOrigin => "403 Forbidden",
+        }
+    }
+
+    pub fn reason(&self) -> &'static str {
+        match *self {
+            Rejection::UnsupportedVersion => "unsupported_version",
+            Rejection::UnsupportedProtocol => "unsupported_protocol",
+            Rejection::ForbiddenOrigin => "forbidden_origin",
+        }
+    }
 }
 
 impl Dispatch {
-    pub fn new(socket: MaybeTlsStream<TcpStream>) -> Dispatch {
+    pub fn new(socket: MaybeTlsStream<TcpStream>, srv: Rc<Server>) -> Dispatch {
         Dispatch {
             socket: Some(socket),
             data: BytesMut::new(),
+            want: 0,
+            srv: srv,
         }
     }
 }
@@ -56,35 +124,149 @@ impl Future for Dispatch {
 
     fn poll(&mut self) -> Poll<(WebpushIo, RequestType), Error> {
         loop {
-            if self.data.len() == self.data.capacity() {
-                self.data.reserve(16); // get some extra space
+            let want = cmp::max(self.want, self.data.len() + 16);
+            if self.data.capacity() < want {
+                let len = self.data.len();
+                self.data.reserve(want - len); // get some extra space
             }
             if try_ready!(self.socket.as_mut().unwrap().read_buf(&mut self.data)) == 0 {
                 return Err("early eof".into());
             }
-            let ty = {
+            let (ty, request_len, deflate) = {
                 let mut headers = [httparse::EMPTY_HEADER; 16];
                 let mut req = httparse::Request::new(&mut headers);
-                match req.parse(&self.data)? {
-                    httparse::Status::Complete(_) => {}
+                let request_len = match req.parse(&self.data)? {
+                    httparse::Status::Complete(n) => n,
                     httparse::Status::Partial => continue,
-                }
+                };
 
                 if req.headers.iter().any(|h| h.name == "Upgrade") {
-                    RequestType::Websocket
+                    let deflate = self.srv.opts.ws_deflate.as_ref().and_then(|opts| {
+                        let offers = req
+                            .headers
+                            .iter()
+                            .filter(|h| h.name.eq_ignore_ascii_case("Sec-WebSocket-Extensions"))
+                            .map(|h| String::from_utf8_lossy(h.value))
+                            .collect::<Vec<_>>();
+                        deflate::negotiate(opts, offers.iter().map(|o| o.as_ref()))
+                    });
+                    match negotiate(&self.srv.opts, req.headers) {
+                        Ok(protocol) => {
+                            let extensions = deflate.as_ref().map(Params::header);
+                            (RequestType::Websocket(protocol, extensions), request_len, deflate)
+                        }
+                        Err(rejection) => (RequestType::Rejected(rejection), request_len, None),
+                    }
                 } else {
-                    match req.path {
-                        Some(ref path) if path.starts_with("/status") => RequestType::Status,
+                    let ty = match (req.method, req.path) {
+                        (Some("GET"), Some(path)) if path == "/sse" || path.starts_with("/sse?") => {
+                            let user_agent = header(req.headers, "User-Agent").unwrap_or_default();
+                            RequestType::SseStream(user_agent.into_owned())
+                        }
+                        (Some("POST"), Some(path)) if path.starts_with("/sse/") => {
+                            let token = path[5..].to_string();
+                            let len = header(req.headers, "Content-Length")
+                                .and_then(|len| len.trim().parse::<usize>().ok())
+                                .unwrap_or(0);
+                            let max = self
+                                .srv
+                                .opts
+                                .max_message_size
+                                .map_or(MAX_SSE_POST, |max| cmp::min(max, MAX_SSE_POST));
+                            if len as u64 > max {
+                                RequestType::SsePost(token, None)
+                            } else if self.data.len() < request_len + len {
+                                self.want = request_len + len;
+                                continue;
+                            } else {
+                                let body = self.data[request_len..request_len + len].to_vec();
+                                RequestType::SsePost(token, Some(body))
+                            }
+                        }
+                        (Some(method), Some(path)) if PushRequest::handles(&self.srv.opts, path) => {
+                            let authorization = header(req.headers, "Authorization");
+                            RequestType::PushService(PushRequest::parse(
+                                &self.srv.opts,
+                                method,
+                                path,
+                                authorization.as_ref().map(|a| a.as_ref()),
+                            ))
+                        }
+                        (_, Some(path)) if path.starts_with("/status") => RequestType::Status,
+                        (_, Some(path)) if path.starts_with("/metrics") => RequestType::Metrics,
                         _ => {
                             debug!("unknown http request {:?}", req);
                             return Err("unknown http request".into());
                         }
-                    }
+                    };
+                    (ty, request_len, None)
                 }
             };
 
+            let websocket = match ty {
+                RequestType::Websocket(..) => true,
+                _ => false,
+            };
             let tcp = self.socket.take().unwrap();
-            return Ok((WebpushIo::new(tcp, self.data.take()), ty).into());
+            let io = WebpushIo::new(
+                tcp,
+                self.data.take(),
+                request_len,
+                websocket,
+                deflate,
+                &self.srv,
+            );
+            return Ok((io, ty).into());
+        }
+    }
+}
+
+fn header<'a>(headers: &[httparse::Header<'a>], name: &str) -> Option<Cow<'a, str>> {
+    headers
+        .iter()
+        .find(|h| h.name.eq_ignore_ascii_case(name))
+        .map(|h| String::from_utf8_lossy(h.value))
+}
+
+/// Checks a websocket upgrade request's headers against what we support,
+/// returning the subprotocol to answer with.
+///
+/// Clients not asking for a subprotocol, or not sending an `Origin` (anything
+/// but a browser page), are let through as before.
+fn negotiate(
+    opts: &ServerOptions,
+    headers: &[httparse::Header],
+) -> ::std::result::Result<Option<String>, Rejection> {
+    let values = |name: &'static str| {
+        headers
+            .iter()
+            .filter(move |h| h.name.eq_ignore_ascii_case(name))
+            .map(|h| String::from_utf8_lossy(h.value))
+    };
+
+    if let Some(version) = values("Sec-WebSocket-Version").next() {
+        if version.trim() != "13" {
+            return Err(Rejection::UnsupportedVersion);
+        }
+    }
+
+    if let Some(ref allowed) = opts.allowed_origins {
+        if let Some(origin) = values("Origin").next() {
+            if !allowed.iter().any(|a| a.eq_ignore_ascii_case(origin.trim())) {
+                return Err(Rejection::ForbiddenOrigin);
+            }
         }
     }
+
+    // The client lists the protocols it speaks in order of preference, over
+    // any number of headers
+    let offered = values("Sec-WebSocket-Protocol").collect::<Vec<Cow<str>>>();
+    let mut offered = offered.iter().flat_map(|v| v.split(',')).map(str::trim).peekable();
+    if offered.peek().is_none() {
+        return Ok(None);
+    }
+    offered
+        .find(|p| opts.ws_subprotocols.iter().any(|s| s.as_str() == *p))
+        .map(|p| Some(p.to_string()))
+        .ok_or(Rejection::UnsupportedProtocol)
 }
@@ -1,15 +1,34 @@
 //! Metrics tie-ins
 
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::io;
 use std::net::UdpSocket;
+use std::sync::{Arc, Mutex};
 
-use cadence::{BufferedUdpMetricSink, NopMetricSink, QueuingMetricSink, StatsdClient};
+use cadence::{BufferedUdpMetricSink, MetricSink, NopMetricSink, QueuingMetricSink, StatsdClient};
 
 use errors::*;
-use server::ServerOptions;
+use server::{MetricsBackend, ServerOptions};
+
+/// Upper bounds of the histogram buckets used for timers and histograms
+/// exposed through Prometheus.
+const BUCKETS: &[u64] = &[
+    1, 5, 10, 25, 50, 100, 250, 500, 1_000, 2_500, 5_000, 10_000, 30_000, 60_000,
+];
 
 /// Create a cadence StatsdClient from the given options
-pub fn metrics_from_opts(opts: &ServerOptions) -> Result<StatsdClient> {
-    Ok(if let Some(statsd_host) = opts.statsd_host.as_ref() {
+///
+/// When the Prometheus backend is selected the returned client emits into an
+/// in-process registry, which is also returned so it can be rendered on
+/// `/metrics`.
+pub fn metrics_from_opts(opts: &ServerOptions) -> Result<(StatsdClient, Option<Prometheus>)> {
+    if let MetricsBackend::Prometheus = opts.metrics_backend {
+        let registry = Prometheus::new();
+        let client = StatsdClient::from_sink("autopush", registry.clone());
+        return Ok((client, Some(registry)));
+    }
+    Ok((if let Some(statsd_host) = opts.statsd_host.as_ref() {
         let socket = UdpSocket::bind("0.0.0.0:0")?;
         socket.set_nonblocking(true)?;
 
@@ -19,5 +38,192 @@ pub fn metrics_from_opts(opts: &ServerOptions) -> Result<StatsdClient> {
         StatsdClient::from_sink("autopush", sink)
     } else {
         StatsdClient::from_sink("autopush", NopMetricSink)
-    })
+    }, None))
+}
+
+/// A `MetricSink` aggregating statsd lines into Prometheus counters, gauges
+/// and histograms.
+///
+/// All metrics in the server are still emitted through a `StatsdClient`, this
+/// sink just parses each line back out as it's emitted and folds it into an
+/// in-process registry. The registry is then rendered in the text exposition
+/// format whenever `/metrics` is scraped. DogStatsD style `key:value` tags are
+/// turned into labels, one series per distinct set of labels.
+#[derive(Clone)]
+pub struct Prometheus {
+    inner: Arc<Mutex<Registry>>,
+}
+
+// Each metric name maps to its series, keyed by the rendered label set (empty
+// for metrics without tags).
+#[derive(Default)]
+struct Registry {
+    counters: BTreeMap<String, BTreeMap<String, i64>>,
+    gauges: BTreeMap<String, BTreeMap<String, u64>>,
+    histograms: BTreeMap<String, BTreeMap<String, Histogram>>,
+}
+
+struct Histogram {
+    buckets: Vec<u64>,
+    sum: u64,
+    count: u64,
+}
+
+impl Prometheus {
+    fn new() -> Prometheus {
+        Prometheus {
+            inner: Arc::new(Mutex::new(Registry::default())),
+        }
+    }
+
+    /// Renders all metrics recorded so far in the Prometheus text exposition
+    /// format.
+    pub fn render(&self) -> String {
+        let registry = self.inner.lock().unwrap();
+        let mut out = String::new();
+        for (name, series) in registry.counters.iter() {
+            writeln!(out, "# TYPE {} counter", name).unwrap();
+            for (labels, value) in series.iter() {
+                writeln!(out, "{}{} {}", name, braced(labels), value).unwrap();
+            }
+        }
+        for (name, series) in registry.gauges.iter() {
+            writeln!(out, "# TYPE {} gauge", name).unwrap();
+            for (labels, value) in series.iter() {
+                writeln!(out, "{}{} {}", name, braced(labels), value).unwrap();
+            }
+        }
+        for (name, series) in registry.histograms.iter() {
+            writeln!(out, "# TYPE {} histogram", name).unwrap();
+            for (labels, histogram) in series.iter() {
+                let sep = if labels.is_empty() { "" } else { "," };
+                for (bound, count) in BUCKETS.iter().zip(histogram.buckets.iter()) {
+                    writeln!(out, "{}_bucket{{{}{}le=\"{}\"}} {}",
+                             name, labels, sep, bound, count).unwrap();
+                }
+                writeln!(out, "{}_bucket{{{}{}le=\"+Inf\"}} {}",
+                         name, labels, sep, histogram.count).unwrap();
+                writeln!(out, "{}_sum{} {}", name, braced(labels), histogram.sum).unwrap();
+                writeln!(out, "{}_count{} {}", name, braced(labels), histogram.count).unwrap();
+            }
+        }
+        out
+    }
+
+    fn record(&self, metric: &str) -> Option<()> {
+        // Lines look like `autopush.ua.command.ack:1|c`, optionally followed
+        // by tags as in `|#ua_os_family:Linux,host:example.com`
+        let mut parts = metric.splitn(2, ':');
+        let name = sanitize(parts.next()?);
+        let mut parts = parts.next()?.split('|');
+        let value = parts.next()?;
+        let kind = parts.next()?;
+        let labels = match parts.next() {
+            Some(tags) if tags.starts_with('#') => tags_to_labels(&tags[1..]),
+            _ => String::new(),
+        };
+
+        let mut registry = self.inner.lock().unwrap();
+        match kind {
+            "c" | "m" => {
+                let series = registry.counters.entry(name).or_insert_with(BTreeMap::new);
+                *series.entry(labels).or_insert(0) += value.parse::<i64>().ok()?;
+            }
+            "g" => {
+                let series = registry.gauges.entry(name).or_insert_with(BTreeMap::new);
+                series.insert(labels, value.parse().ok()?);
+            }
+            "ms" | "h" => {
+                let value = value.parse().ok()?;
+                registry
+                    .histograms
+                    .entry(name)
+                    .or_insert_with(BTreeMap::new)
+                    .entry(labels)
+                    .or_insert_with(Histogram::new)
+                    .observe(value);
+            }
+            _ => return None,
+        }
+        Some(())
+    }
+}
+
+impl MetricSink for Prometheus {
+    fn emit(&self, metric: &str) -> io::Result<usize> {
+        match self.record(metric) {
+            Some(()) => Ok(metric.len()),
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unrecognized metric: {}", metric),
+            )),
+        }
+    }
+}
+
+impl Histogram {
+    fn new() -> Histogram {
+        Histogram {
+            buckets: vec![0; BUCKETS.len()],
+            sum: 0,
+            count: 0,
+        }
+    }
+
+    fn observe(&mut self, value: u64) {
+        for (bound, count) in BUCKETS.iter().zip(self.buckets.iter_mut()) {
+            if value <= *bound {
+                *count += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+/// Renders DogStatsD tags (`a:1,b:2`) as a Prometheus label set (`a="1",b="2"`).
+///
+/// Tags without a value have no sensible label equivalent and are dropped.
+fn tags_to_labels(tags: &str) -> String {
+    let mut out = String::new();
+    for tag in tags.split(',') {
+        let mut parts = tag.splitn(2, ':');
+        let (key, value) = match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => (key, value),
+            _ => continue,
+        };
+        if !out.is_empty() {
+            out.push(',');
+        }
+        out.push_str(&sanitize(key));
+        out.push_str("=\"");
+        for c in value.chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '"' => out.push_str("\\\""),
+                '\n' => out.push_str("\\n"),
+                _ => out.push(c),
+            }
+        }
+        out.push('"');
+    }
+    out
+}
+
+fn braced(labels: &str) -> String {
+    if labels.is_empty() {
+        String::new()
+    } else {
+        format!("{{{}}}", labels)
+    }
+}
+
+/// Maps a statsd metric name onto the characters Prometheus allows.
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '_' | ':' => c,
+            _ => '_',
+        })
+        .collect()
 }
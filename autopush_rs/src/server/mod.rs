@@ -7,17 +7,19 @@ use std::panic;
 use std::panic::PanicInfo;
 use std::path::PathBuf;
 use std::rc::Rc;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Instant, Duration};
 
+use cadence::prelude::*;
 use cadence::StatsdClient;
 use futures;
-use futures::sync::oneshot;
+use futures::sync::{mpsc, oneshot};
 use futures::task;
 use futures::{Stream, Future, Sink, Async, Poll, AsyncSink, StartSend};
 use libc::c_char;
 use openssl::ssl::SslAcceptor;
+use rmp_serde;
 use sentry;
 use serde_json;
 use time;
@@ -29,24 +31,40 @@ use tungstenite::handshake::server::Request;
 use tungstenite::Message;
 use uuid::Uuid;
 
+use call::{CallEncoding, CircuitBreaker, RetryPolicy};
 use client::{Client, RegisteredClient};
 use errors::*;
 use errors::{Error, Result};
-use protocol::{ClientMessage, ServerMessage, ServerNotification, Notification};
+use protocol::{ClientMessage, Encoding, ServerMessage, ServerNotification, Notification};
 use queue::{self, AutopushQueue};
+use receipts::ReceiptClient;
+use spool::{self, Spool};
 use rt::{self, AutopushError, UnwindGuard};
-use server::dispatch::{Dispatch, RequestType};
-use server::metrics::metrics_from_opts;
+use server::deflate::DeflateOptions;
+use server::dispatch::{Dispatch, Rejection, RequestType};
+use server::metrics::{metrics_from_opts, Prometheus};
+use server::stats::{AutopushServerStats, Snapshot, Stats};
+use server::tls::RouterTls;
 use server::webpush_io::WebpushIo;
 use util::{self, RcObject, timeout};
 
+mod deflate;
 mod dispatch;
 mod metrics;
+mod push_service;
+mod sse;
+pub mod stats;
 mod tls;
 mod webpush_io;
 
 const UAHEADER: &str = "User-Agent";
+const PROTOCOL_HEADER: &str = "Sec-WebSocket-Protocol";
+const EXTENSIONS_HEADER: &str = "Sec-WebSocket-Extensions";
 
+/// A handle to the server running on its own tokio thread
+///
+/// Python may call into this from any of its threads at once (cffi releases
+/// the GIL around calls), so everything in here is behind a `Mutex`.
 #[repr(C)]
 pub struct AutopushServer {
     inner: UnwindGuard<AutopushServerInner>,
@@ -55,10 +73,43 @@ pub struct AutopushServer {
 struct AutopushServerInner {
     opts: Arc<ServerOptions>,
     // Used when shutting down a server
-    tx: Cell<Option<oneshot::Sender<()>>>,
-    thread: Cell<Option<thread::JoinHandle<()>>>,
+    tx: Mutex<Option<oneshot::Sender<()>>>,
+    // Used to hand requests over to the server running on the tokio thread
+    control: Mutex<Option<mpsc::UnboundedSender<Control>>>,
+    thread: Mutex<Option<thread::JoinHandle<()>>>,
 }
 
+fn _assert_kinds() {
+    fn _assert<T: Send + Sync>() {}
+    _assert::<AutopushServer>();
+}
+
+/// Requests sent from Python to the `Server` on its tokio thread
+enum Control {
+    /// Rebuild the TLS acceptor from the configured files, replying with
+    /// whether that succeeded
+    ReloadTls(oneshot::Sender<Result<()>>),
+    /// Hand a notification to the client connected with the uaid, replying
+    /// with whether it took it
+    Notify(Uuid, Notification, oneshot::Sender<Result<()>>),
+    /// Let the client connected with the uaid know it has notifications
+    /// stored, replying with whether it's connected
+    CheckStorage(Uuid, oneshot::Sender<Result<()>>),
+    /// Take a snapshot of the server's statistics
+    Stats(oneshot::Sender<Result<Snapshot>>),
+}
+
+/// `autopush_server_notify` and `autopush_server_check_storage` outcomes: the
+/// client connected here has been told
+pub const NOTIFY_DELIVERED: usize = 0;
+/// No client is connected here with the uaid
+pub const NOTIFY_NOT_CONNECTED: usize = 1;
+/// The client connected here has too many notifications waiting already,
+/// store this one instead
+pub const NOTIFY_BACKPRESSURE: usize = 2;
+/// The uaid or notification passed in couldn't be parsed
+pub const NOTIFY_INVALID: usize = 3;
+
 #[repr(C)]
 pub struct AutopushServerOptions {
     pub debug: i32,
@@ -70,6 +121,31 @@ pub struct AutopushServerOptions {
     pub ssl_key: *const c_char,
     pub ssl_cert: *const c_char,
     pub ssl_dh_param: *const c_char,
+    pub ssl_chain: *const c_char,
+    pub ssl_sni_certs: *const c_char,
+    pub router_ssl_key: *const c_char,
+    pub router_ssl_cert: *const c_char,
+    pub router_ssl_ca: *const c_char,
+    pub router_client_certs: *const c_char,
+    pub ws_subprotocols: *const c_char,
+    pub allowed_origins: *const c_char,
+    pub ws_deflate: i32,
+    pub ws_deflate_window_bits: u32,
+    pub ws_deflate_context_takeover: i32,
+    pub ws_deflate_memory: u32,
+    pub push_service_key: *const c_char,
+    pub max_message_size: u32,
+    pub max_frame_size: u32,
+    pub max_send_queue: u32,
+    pub client_queue_depth: u32,
+    pub local_receipts: i32,
+    pub spool_path: *const c_char,
+    pub spool_max_bytes: u64,
+    pub call_retries: *const c_char,
+    pub call_timeouts: *const c_char,
+    pub call_encoding: *const c_char,
+    pub breaker_threshold: u32,
+    pub breaker_cooldown: f64,
     pub open_handshake_timeout: u32,
     pub auto_ping_interval: f64,
     pub auto_ping_timeout: f64,
@@ -78,12 +154,21 @@ pub struct AutopushServerOptions {
     pub json_logging: i32,
     pub statsd_host: *const c_char,
     pub statsd_port: u16,
+    pub metrics_backend: *const c_char,
 }
 
 pub struct Server {
     uaids: RefCell<HashMap<Uuid, RegisteredClient>>,
     open_connections: Cell<u32>,
-    tls_acceptor: Option<SslAcceptor>,
+    // Event streams of the SSE transport, by session token
+    sse_sessions: RefCell<HashMap<Uuid, mpsc::UnboundedSender<ClientMessage>>>,
+    tls_acceptor: RefCell<Option<SslAcceptor>>,
+    router_tls: Option<RouterTls>,
+    prometheus: Option<Prometheus>,
+    pub stats: Stats,
+    pub receipts: ReceiptClient,
+    pub spool: Option<Spool>,
+    pub breaker: Rc<CircuitBreaker>,
     pub tx: queue::Sender,
     pub opts: Arc<ServerOptions>,
     pub handle: Handle,
@@ -100,6 +185,49 @@ pub struct ServerOptions {
     pub ssl_key: Option<PathBuf>,
     pub ssl_cert: Option<PathBuf>,
     pub ssl_dh_param: Option<PathBuf>,
+    pub ssl_chain: Option<PathBuf>,
+    pub ssl_sni_certs: Vec<CertConfig>,
+    pub router_ssl_key: Option<PathBuf>,
+    pub router_ssl_cert: Option<PathBuf>,
+    pub router_ssl_ca: Option<PathBuf>,
+    /// Allowed router client certificate fingerprints, mapped to their names
+    pub router_client_certs: HashMap<String, String>,
+    /// Websocket subprotocols clients may negotiate
+    pub ws_subprotocols: Vec<String>,
+    /// Origins allowed to open a websocket, `None` allowing all of them
+    pub allowed_origins: Option<Vec<String>>,
+    /// How permessage-deflate compression is offered, if at all
+    pub ws_deflate: Option<DeflateOptions>,
+    /// Bearer token `POST /subscribe` requests must carry, the RFC 8030
+    /// resources being off without one
+    pub push_service_key: Option<String>,
+    /// Largest websocket message (in bytes) accepted from clients
+    pub max_message_size: Option<u64>,
+    /// Largest single websocket frame (in bytes) accepted from clients
+    pub max_frame_size: Option<u64>,
+    /// Most messages queued up to a client before sends wait for a flush
+    pub max_send_queue: Option<usize>,
+    /// Notifications buffered for a client before further ones are refused,
+    /// leaving them to be stored
+    pub client_queue_depth: usize,
+    /// Whether receipts for notifications without a receipt URL are handed
+    /// to Python
+    pub local_receipts: bool,
+    /// Where notifications that failed to be stored are spooled, if anywhere
+    pub spool_path: Option<PathBuf>,
+    /// Largest the spool may grow to (in bytes), if limited
+    pub spool_max_bytes: Option<u64>,
+    /// How failed calls into Python are retried, by command
+    pub call_retries: HashMap<String, RetryPolicy>,
+    /// How long Python gets to complete each attempt of a call, by command
+    pub call_timeouts: HashMap<String, Duration>,
+    /// How calls into Python and their results are encoded
+    pub call_encoding: CallEncoding,
+    /// Python calls failing in a row before new clients are turned away, 0
+    /// never turning them away
+    pub breaker_threshold: u32,
+    /// How long new clients are turned away for
+    pub breaker_cooldown: Duration,
     pub open_handshake_timeout: Option<Duration>,
     pub auto_ping_interval: Duration,
     pub auto_ping_timeout: Duration,
@@ -107,9 +235,27 @@ pub struct ServerOptions {
     pub close_handshake_timeout: Option<Duration>,
     pub statsd_host: Option<String>,
     pub statsd_port: u16,
+    pub metrics_backend: MetricsBackend,
     pub logger: util::LogGuards,
 }
 
+/// An additional certificate served to clients asking for one of its
+/// hostnames through SNI
+#[derive(Deserialize)]
+pub struct CertConfig {
+    pub key: PathBuf,
+    pub cert: PathBuf,
+    pub chain: Option<PathBuf>,
+}
+
+/// Where metrics emitted by the server end up
+pub enum MetricsBackend {
+    /// Sent over UDP to `statsd_host`, if configured
+    Statsd,
+    /// Aggregated in-process and served at `/metrics`
+    Prometheus,
+}
+
 fn resolve(host: &str) -> IpAddr {
     (host, 0).to_socket_addrs().unwrap().next().unwrap().ip()
 }
@@ -127,6 +273,14 @@ pub extern "C" fn autopush_server_new(
         if s.is_empty() { None } else { Some(s) }
     }
 
+    fn split_list(s: &str) -> Vec<String> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
     unsafe fn ito_dur(seconds: u32) -> Option<Duration> {
         if seconds == 0 {
             None
@@ -166,10 +320,99 @@ pub extern "C" fn autopush_server_new(
             router_port: opts.router_port,
             statsd_host: to_s(opts.statsd_host).map(|s| s.to_string()),
             statsd_port: opts.statsd_port,
+            metrics_backend: match to_s(opts.metrics_backend) {
+                None | Some("statsd") => MetricsBackend::Statsd,
+                Some("prometheus") => MetricsBackend::Prometheus,
+                Some(other) => panic!("invalid metrics backend: {}", other),
+            },
             url: to_s(opts.url).expect("url must be specified").to_string(),
             ssl_key: to_s(opts.ssl_key).map(PathBuf::from),
             ssl_cert: to_s(opts.ssl_cert).map(PathBuf::from),
             ssl_dh_param: to_s(opts.ssl_dh_param).map(PathBuf::from),
+            ssl_chain: to_s(opts.ssl_chain).map(PathBuf::from),
+            ssl_sni_certs: to_s(opts.ssl_sni_certs)
+                .map(|s| serde_json::from_str(s).expect("invalid ssl_sni_certs"))
+                .unwrap_or_default(),
+            router_ssl_key: to_s(opts.router_ssl_key).map(PathBuf::from),
+            router_ssl_cert: to_s(opts.router_ssl_cert).map(PathBuf::from),
+            router_ssl_ca: to_s(opts.router_ssl_ca).map(PathBuf::from),
+            router_client_certs: to_s(opts.router_client_certs)
+                .map(|s| {
+                    // Same `{"name": ["fingerprint", ..]}` layout as the
+                    // endpoint's `client_certs`
+                    let certs: HashMap<String, Vec<String>> =
+                        serde_json::from_str(s).expect("invalid router_client_certs");
+                    certs
+                        .into_iter()
+                        .flat_map(|(name, sigs)| {
+                            sigs.into_iter().map(move |sig| (sig.to_uppercase(), name.clone()))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            ws_subprotocols: to_s(opts.ws_subprotocols).map(split_list).unwrap_or_default(),
+            allowed_origins: to_s(opts.allowed_origins).map(split_list),
+            ws_deflate: if opts.ws_deflate == 0 {
+                None
+            } else {
+                assert!(
+                    opts.ws_deflate_window_bits >= 9 && opts.ws_deflate_window_bits <= 15,
+                    "ws_deflate_window_bits must be within 9 and 15"
+                );
+                Some(DeflateOptions {
+                    window_bits: opts.ws_deflate_window_bits as u8,
+                    context_takeover: opts.ws_deflate_context_takeover != 0,
+                    memory: if opts.ws_deflate_memory == 0 {
+                        None
+                    } else {
+                        Some(opts.ws_deflate_memory as usize)
+                    },
+                })
+            },
+            push_service_key: to_s(opts.push_service_key).map(|s| s.to_string()),
+            max_message_size: if opts.max_message_size == 0 {
+                None
+            } else {
+                Some(opts.max_message_size.into())
+            },
+            max_frame_size: if opts.max_frame_size == 0 {
+                None
+            } else {
+                Some(opts.max_frame_size.into())
+            },
+            max_send_queue: if opts.max_send_queue == 0 {
+                None
+            } else {
+                Some(opts.max_send_queue as usize)
+            },
+            client_queue_depth: opts.client_queue_depth as usize,
+            local_receipts: opts.local_receipts != 0,
+            spool_path: to_s(opts.spool_path).map(PathBuf::from),
+            spool_max_bytes: if opts.spool_max_bytes == 0 {
+                None
+            } else {
+                Some(opts.spool_max_bytes)
+            },
+            call_retries: to_s(opts.call_retries)
+                .map(|s| serde_json::from_str(s).expect("invalid call_retries"))
+                .unwrap_or_default(),
+            call_timeouts: to_s(opts.call_timeouts)
+                .map(|s| {
+                    let timeouts: HashMap<String, f64> =
+                        serde_json::from_str(s).expect("invalid call_timeouts");
+                    timeouts
+                        .into_iter()
+                        .filter_map(|(command, secs)| fto_dur(secs).map(|dur| (command, dur)))
+                        .collect()
+                })
+                .unwrap_or_default(),
+            call_encoding: match to_s(opts.call_encoding) {
+                None | Some("json") => CallEncoding::Json,
+                Some("msgpack") => CallEncoding::MessagePack,
+                Some(other) => panic!("invalid call encoding: {}", other),
+            },
+            breaker_threshold: opts.breaker_threshold,
+            breaker_cooldown: fto_dur(opts.breaker_cooldown).unwrap_or_default(),
             auto_ping_interval: fto_dur(opts.auto_ping_interval).expect(
                 "ping interval cannot be 0",
             ),
@@ -187,8 +430,9 @@ pub extern "C" fn autopush_server_new(
         Box::new(AutopushServer {
             inner: UnwindGuard::new(AutopushServerInner {
                 opts: Arc::new(opts),
-                tx: Cell::new(None),
-                thread: Cell::new(None),
+                tx: Mutex::new(None),
+                control: Mutex::new(None),
+                thread: Mutex::new(None),
             }),
         })
     })
@@ -203,9 +447,11 @@ pub extern "C" fn autopush_server_start(
     unsafe {
         (*srv).inner.catch(err, |srv| {
             let tx = (*queue).tx();
-            let (tx, thread) = Server::start(&srv.opts, tx).expect("failed to start server");
-            srv.tx.set(Some(tx));
-            srv.thread.set(Some(thread));
+            let (tx, control, thread) =
+                Server::start(&srv.opts, tx).expect("failed to start server");
+            *srv.tx.lock().unwrap() = Some(tx);
+            *srv.control.lock().unwrap() = Some(control);
+            *srv.thread.lock().unwrap() = Some(thread);
         })
     }
 }
@@ -219,6 +465,100 @@ pub extern "C" fn autopush_server_stop(srv: *mut AutopushServer, err: &mut Autop
     }
 }
 
+/// Swaps in the TLS key and certificates currently on disk. Returns 1 if they
+/// were, or 0 if they failed to load (or the server isn't running) and the
+/// previous ones stay in use.
+#[no_mangle]
+pub extern "C" fn autopush_server_reload_tls(
+    srv: *mut AutopushServer,
+    err: &mut AutopushError,
+) -> usize {
+    unsafe {
+        (*srv)
+            .inner
+            .catch(err, |srv| srv.reload_tls().is_ok() as usize)
+    }
+}
+
+/// Hands the JSON notification `notif` to the client connected with `uaid`,
+/// like a request to the router's `/push/<uaid>` would. Returns one of the
+/// `NOTIFY_*` codes.
+#[no_mangle]
+pub extern "C" fn autopush_server_notify(
+    srv: *mut AutopushServer,
+    uaid: *const c_char,
+    notif: *const c_char,
+    err: &mut AutopushError,
+) -> usize {
+    unsafe {
+        (*srv).inner.catch(err, |srv| {
+            let uaid = match parse_uaid(uaid) {
+                Some(uaid) => uaid,
+                None => return NOTIFY_INVALID,
+            };
+            let notif = CStr::from_ptr(notif).to_str().ok();
+            let notif: Notification = match notif.and_then(|s| serde_json::from_str(s).ok()) {
+                Some(notif) => notif,
+                None => return NOTIFY_INVALID,
+            };
+            notify_status(srv.request(|tx| Control::Notify(uaid, notif, tx)))
+        })
+    }
+}
+
+/// Lets the client connected with `uaid` know it has notifications stored,
+/// like a request to the router's `/notif/<uaid>` would. Returns one of the
+/// `NOTIFY_*` codes, never `NOTIFY_BACKPRESSURE`.
+#[no_mangle]
+pub extern "C" fn autopush_server_check_storage(
+    srv: *mut AutopushServer,
+    uaid: *const c_char,
+    err: &mut AutopushError,
+) -> usize {
+    unsafe {
+        (*srv).inner.catch(err, |srv| {
+            let uaid = match parse_uaid(uaid) {
+                Some(uaid) => uaid,
+                None => return NOTIFY_INVALID,
+            };
+            notify_status(srv.request(|tx| Control::CheckStorage(uaid, tx)))
+        })
+    }
+}
+
+/// Takes a snapshot of the server's statistics, to be read out as JSON with
+/// `autopush_server_stats_ptr` and `autopush_server_stats_len`. Returns 1
+/// rather than a snapshot if the server isn't running.
+#[no_mangle]
+pub extern "C" fn autopush_server_stats(
+    srv: *mut AutopushServer,
+    err: &mut AutopushError,
+) -> *mut AutopushServerStats {
+    unsafe {
+        (*srv).inner.catch(err, |srv| {
+            srv.request(Control::Stats)
+                .ok()
+                .map(|snapshot| Box::new(AutopushServerStats::new(&snapshot)))
+        })
+    }
+}
+
+unsafe fn parse_uaid(uaid: *const c_char) -> Option<Uuid> {
+    CStr::from_ptr(uaid)
+        .to_str()
+        .ok()
+        .and_then(|uaid| Uuid::parse_str(uaid).ok())
+}
+
+fn notify_status(res: Result<()>) -> usize {
+    match res {
+        Ok(()) => NOTIFY_DELIVERED,
+        Err(Error(ErrorKind::ClientBackpressure, _)) => NOTIFY_BACKPRESSURE,
+        // Including the server not running, so nobody's connected
+        Err(_) => NOTIFY_NOT_CONNECTED,
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn autopush_server_free(srv: *mut AutopushServer) {
     rt::abort_on_panic(|| unsafe {
@@ -230,12 +570,44 @@ impl AutopushServerInner {
     /// Blocks execution of the calling thread until the helper thread with the
     /// tokio reactor has exited.
     fn stop(&self) -> Result<()> {
-        drop(self.tx.take());
-        if let Some(thread) = self.thread.take() {
+        drop(self.tx.lock().unwrap().take());
+        drop(self.control.lock().unwrap().take());
+        let thread = self.thread.lock().unwrap().take();
+        if let Some(thread) = thread {
             thread.join().map_err(ErrorKind::Thread)?;
         }
         Ok(())
     }
+
+    /// Swaps in a new TLS acceptor built from the current contents of the
+    /// configured key and certificate files.
+    ///
+    /// Blocks until the tokio thread has either validated and installed the
+    /// new configuration or rejected it, in which case the previous one stays
+    /// in use. Connections already established are unaffected either way.
+    fn reload_tls(&self) -> Result<()> {
+        self.request(Control::ReloadTls)
+    }
+
+    /// Sends the tokio thread the `Control` message made by `msg` and blocks
+    /// until it has replied.
+    fn request<T, F>(&self, msg: F) -> Result<T>
+    where
+        F: FnOnce(oneshot::Sender<Result<T>>) -> Control,
+    {
+        let (tx, rx) = oneshot::channel();
+        // Only hold the lock long enough to send, other threads may be
+        // waiting on replies of their own
+        let control = self.control
+            .lock()
+            .unwrap()
+            .clone()
+            .ok_or("server not running")?;
+        control
+            .unbounded_send(msg(tx))
+            .map_err(|_| "server not running")?;
+        rx.wait().chain_err(|| "server not running")?
+    }
 }
 
 impl Drop for AutopushServerInner {
@@ -254,9 +626,14 @@ impl Server {
     fn start(
         opts: &Arc<ServerOptions>,
         tx: queue::Sender,
-    ) -> Result<(oneshot::Sender<()>, thread::JoinHandle<()>)> {
+    ) -> Result<(
+        oneshot::Sender<()>,
+        mpsc::UnboundedSender<Control>,
+        thread::JoinHandle<()>,
+    )> {
         let (donetx, donerx) = oneshot::channel();
         let (inittx, initrx) = oneshot::channel();
+        let (controltx, controlrx) = mpsc::unbounded();
 
         let opts = opts.clone();
         let thread = thread::spawn(move || {
@@ -268,6 +645,12 @@ impl Server {
                 Err(e) => return inittx.send(Some(e)).unwrap(),
             };
 
+            let srv2 = srv.clone();
+            core.handle().spawn(controlrx.for_each(move |msg| {
+                srv2.control(msg);
+                Ok(())
+            }));
+
             // Internal HTTP server setup
             {
                 use hyper::server::Http;
@@ -280,7 +663,22 @@ impl Server {
                 let push_listener = TcpListener::bind(&addr, &handle).unwrap();
                 let proto = Http::new();
                 let push_srv = push_listener.incoming().for_each(move |(socket, addr)| {
-                    proto.bind_connection(&handle, socket, addr, ::http::Push(srv.clone()));
+                    let handle = handle.clone();
+                    let proto = proto.clone();
+                    let srv2 = srv.clone();
+                    let conn = tls::accept_router(&srv, socket).map(move |(socket, authorized)| {
+                        let push = ::http::Push {
+                            srv: srv2,
+                            authorized: authorized,
+                        };
+                        proto.bind_connection(&handle, socket, addr, push);
+                    });
+                    srv.handle.spawn(conn.then(|res| {
+                        if let Err(e) = res {
+                            debug!("Failed to accept router connection: {}", e);
+                        }
+                        Ok(())
+                    }));
                     Ok(())
                 });
                 core.handle().spawn(push_srv.then(|res| {
@@ -294,7 +692,7 @@ impl Server {
 
         match initrx.wait() {
             Ok(Some(e)) => Err(e),
-            Ok(None) => Ok((donetx, thread)),
+            Ok(None) => Ok((donetx, controltx, thread)),
             Err(_) => panic::resume_unwind(thread.join().unwrap_err()),
         }
     }
@@ -320,15 +718,30 @@ impl Server {
         }
 
         let core = Core::new()?;
+        let (metrics, prometheus) = metrics_from_opts(opts)?;
         let srv = Rc::new(Server {
             opts: opts.clone(),
             uaids: RefCell::new(HashMap::new()),
+            sse_sessions: RefCell::new(HashMap::new()),
             open_connections: Cell::new(0),
             handle: core.handle(),
             tx: tx,
-            tls_acceptor: tls::configure(opts),
-            metrics: metrics_from_opts(opts)?,
+            tls_acceptor: RefCell::new(tls::configure(opts)?),
+            router_tls: RouterTls::configure(opts)?,
+            prometheus: prometheus,
+            stats: Stats::new(),
+            receipts: ReceiptClient::new(&core.handle())?,
+            spool: match opts.spool_path {
+                Some(ref path) => Some(Spool::open(path, opts.spool_max_bytes)?),
+                None => None,
+            },
+            breaker: Rc::new(CircuitBreaker::new(
+                opts.breaker_threshold,
+                opts.breaker_cooldown,
+            )),
+            metrics: metrics,
         });
+        spool::replay(&srv);
         let host_ip = resolve(&srv.opts.host_ip);
         let addr = format!("{}:{}", host_ip, srv.opts.port);
         let ws_listener = TcpListener::bind(&addr.parse().unwrap(), &srv.handle)?;
@@ -350,6 +763,11 @@ impl Server {
                     );
                     return Ok(());
                 }
+                // Every connection is counted here, before we know what it's
+                // for, and only let go of once the future serving it below
+                // resolves. SSE streams are served by that future for the
+                // whole session, so they hold their slot just as long as
+                // websocket clients do.
                 srv.open_connections.set(srv.open_connections.get() + 1);
 
                 // TODO: TCP socket options here?
@@ -358,7 +776,8 @@ impl Server {
                 let socket = tls::accept(&srv, socket);
 
                 // Figure out if this is a websocket or a `/status` request,
-                let request = socket.and_then(Dispatch::new);
+                let srv3 = srv.clone();
+                let request = socket.and_then(move |socket| Dispatch::new(socket, srv3));
 
                 // Time out both the TLS accept (if any) along with the dispatch
                 // to figure out where we're going.
@@ -370,24 +789,59 @@ impl Server {
 
                 // Setup oneshot to extract the user-agent from the header callback
                 let (uatx, uarx) = oneshot::channel();
-                let callback = |req: &Request| {
-                    if let Some(value) = req.headers.find_first(UAHEADER) {
-                        let mut valstr = String::new();
-                        for c in value.iter() {
-                            let c = *c as char;
-                            valstr.push(c);
-                        }
-                        debug!("Found user-agent string"; "user-agent" => valstr.as_str());
-                        uatx.send(valstr).unwrap();
-                    }
-                    debug!("No agent string found");
-                    Ok(None)
-                };
 
                 let client = request.and_then(move |(socket, request)| -> MyFuture<_> {
+                    // Nor take on new clients while Python has more work than
+                    // it can keep up with, still answering everything else
+                    let new_client = match request {
+                        RequestType::Websocket(..) | RequestType::SseStream(..) => true,
+                        _ => false,
+                    };
+                    if new_client && srv2.tx.is_saturated() {
+                        info!("dropping {} as python calls are backed up", addr);
+                        srv2.metrics.incr("ua.connection.shed").ok();
+                        return Box::new(futures::future::ok(()));
+                    }
                     match request {
                         RequestType::Status => write_status(socket),
-                        RequestType::Websocket => {
+                        RequestType::Metrics => write_metrics(socket, srv2.prometheus.as_ref()),
+                        RequestType::SseStream(user_agent) => {
+                            sse::stream(&srv2, socket, user_agent, host)
+                        }
+                        RequestType::SsePost(token, body) => sse::post(&srv2, socket, &token, body),
+                        RequestType::PushService(req) => push_service::serve(&srv2, socket, req),
+                        RequestType::Rejected(rejection) => {
+                            debug!("Rejecting websocket handshake"; "reason" => rejection.reason());
+                            srv2.metrics
+                                .incr_with_tags("ua.websocket.rejected")
+                                .with_tag("reason", rejection.reason())
+                                .send()
+                                .ok();
+                            write_rejection(socket, rejection)
+                        }
+                        RequestType::Websocket(protocol, extensions) => {
+                            let encoding = Encoding::for_protocol(protocol.as_ref().map(|p| p.as_str()));
+                            let callback = move |req: &Request| {
+                                if let Some(value) = req.headers.find_first(UAHEADER) {
+                                    let mut valstr = String::new();
+                                    for c in value.iter() {
+                                        let c = *c as char;
+                                        valstr.push(c);
+                                    }
+                                    debug!("Found user-agent string"; "user-agent" => valstr.as_str());
+                                    uatx.send(valstr).unwrap();
+                                }
+                                debug!("No agent string found");
+                                let mut headers = Vec::new();
+                                if let Some(protocol) = protocol {
+                                    headers.push((PROTOCOL_HEADER.to_string(), protocol));
+                                }
+                                if let Some(extensions) = extensions {
+                                    headers.push((EXTENSIONS_HEADER.to_string(), extensions));
+                                }
+                                Ok(if headers.is_empty() { None } else { Some(headers) })
+                            };
+
                             // Perform the websocket handshake on each
                             // connection, but don't let it take too long.
                             let ws = accept_hdr_async(socket, callback).chain_err(|| "failed to accept client");
@@ -399,7 +853,7 @@ impl Server {
                             // the internal state machine.
                             Box::new(
                                 ws.and_then(move |ws| {
-                                    PingManager::new(&srv2, ws, uarx, host).chain_err(
+                                    PingManager::new(&srv2, ws, uarx, host, encoding).chain_err(
                                         || "failed to make ping handler",
                                     )
                                 }).flatten(),
@@ -448,19 +902,47 @@ impl Server {
     }
 
     /// A notification has come for the uaid
+    ///
+    /// Fails with `ClientBackpressure` when the client already has
+    /// `client_queue_depth` notifications waiting, in which case the router
+    /// stores the notification and asks for a storage check instead.
     pub fn notify_client(&self, uaid: Uuid, notif: Notification) -> Result<()> {
         let mut uaids = self.uaids.borrow_mut();
-        if let Some(client) = uaids.get_mut(&uaid) {
-            debug!("Found a client to deliver a notification to");
-            // TODO: Don't unwrap, handle error properly
-            client
-                .tx
-                .unbounded_send(ServerNotification::Notification(notif))
-                .unwrap();
-            debug!("Dropped notification in queue");
-            return Ok(());
+        let client = match uaids.get_mut(&uaid) {
+            Some(client) => client,
+            None => return Err(ErrorKind::ClientNotConnected.into()),
+        };
+        debug!("Found a client to deliver a notification to");
+        match client.tx.try_send(ServerNotification::Notification(notif)) {
+            Ok(()) => {
+                debug!("Dropped notification in queue");
+                Ok(())
+            }
+            Err(ref e) if e.is_full() => {
+                self.metrics.incr("ua.notification.backpressure").ok();
+                Err(ErrorKind::ClientBackpressure.into())
+            }
+            // The client is going away and just hasn't disconnected yet
+            Err(_) => Err(ErrorKind::ClientNotConnected.into()),
+        }
+    }
+
+    /// Notifications for the uaid have been stored
+    pub fn check_client_storage(&self, uaid: Uuid) -> Result<()> {
+        let mut uaids = self.uaids.borrow_mut();
+        let client = match uaids.get_mut(&uaid) {
+            Some(client) => client,
+            None => return Err(ErrorKind::ClientNotConnected.into()),
+        };
+        match client.tx.try_send(ServerNotification::CheckStorage) {
+            Ok(()) => Ok(()),
+            // The client gets to it once it's through its queue
+            Err(ref e) if e.is_full() => {
+                client.check_pending.set(true);
+                Ok(())
+            }
+            Err(_) => Err(ErrorKind::ClientNotConnected.into()),
         }
-        Err("User not connected".into())
     }
 
     /// The client specified by `uaid` has disconnected.
@@ -469,12 +951,37 @@ impl Server {
         let mut uaids = self.uaids.borrow_mut();
         uaids.remove(uaid).expect("uaid not registered");
     }
+
+    /// Handles a request sent over from Python through `AutopushServerInner`
+    fn control(&self, msg: Control) {
+        match msg {
+            Control::ReloadTls(tx) => {
+                let res = tls::configure(&self.opts).map(|acceptor| {
+                    *self.tls_acceptor.borrow_mut() = acceptor;
+                });
+                match res {
+                    Ok(()) => info!("Reloaded TLS configuration"),
+                    Err(ref e) => error!("Failed to reload TLS configuration: {}", e),
+                }
+                drop(tx.send(res));
+            }
+            Control::Notify(uaid, notif, tx) => {
+                drop(tx.send(self.notify_client(uaid, notif)));
+            }
+            Control::CheckStorage(uaid, tx) => {
+                drop(tx.send(self.check_client_storage(uaid)));
+            }
+            Control::Stats(tx) => {
+                drop(tx.send(Ok(self.stats_snapshot())));
+            }
+        }
+    }
 }
 
 impl Drop for Server {
     fn drop(&mut self) {
         // we're done sending messages, close out the queue
-        drop(self.tx.send(None));
+        self.tx.close();
     }
 }
 
@@ -502,7 +1009,8 @@ impl PingManager {
         srv: &Rc<Server>,
         socket: WebSocketStream<WebpushIo>,
         uarx: oneshot::Receiver<String>,
-        host: String)
+        host: String,
+        encoding: Encoding)
         -> io::Result<PingManager> {
         // The `socket` is itself a sink and a stream, and we've also got a sink
         // (`tx`) and a stream (`rx`) to send messages. Half of our job will be
@@ -518,7 +1026,7 @@ impl PingManager {
         // To make these tasks easier we start out by throwing the `socket` into
         // an `Rc` object. This'll allow us to share it between the ping/pong
         // management and message shuffling.
-        let socket = RcObject::new(WebpushSocket::new(socket));
+        let socket = RcObject::new(WebpushSocket::new(socket, encoding, srv.opts.max_send_queue));
         Ok(PingManager {
             timeout: Timeout::new(srv.opts.auto_ping_interval, &srv.handle)?,
             waiting: WaitingFor::SendPing,
@@ -629,15 +1137,22 @@ impl Future for PingManager {
 // `ClientMessage` and `ServerMessage`.
 struct WebpushSocket<T> {
     inner: T,
+    encoding: Encoding,
+    max_send_queue: Option<usize>,
+    // Messages handed to `inner` since it was last completely flushed
+    unflushed: usize,
     pong_received: bool,
     ping: bool,
     pong_timeout: bool,
 }
 
 impl<T> WebpushSocket<T> {
-    fn new(t: T) -> WebpushSocket<T> {
+    fn new(t: T, encoding: Encoding, max_send_queue: Option<usize>) -> WebpushSocket<T> {
         WebpushSocket {
             inner: t,
+            encoding: encoding,
+            max_send_queue: max_send_queue,
+            unflushed: 0,
             pong_received: false,
             ping: false,
             pong_timeout: false,
@@ -696,6 +1211,11 @@ where
                     return Ok(Some(msg).into());
                 }
 
+                Message::Binary(ref b) if self.encoding == Encoding::MessagePack => {
+                    let msg = rmp_serde::from_slice(b).chain_err(|| "invalid msgpack")?;
+                    return Ok(Some(msg).into());
+                }
+
                 Message::Binary(_) => return Err("binary messages not accepted".into()),
 
                 // sending a pong is already managed by lower layers, just go to
@@ -726,18 +1246,36 @@ where
         if self.send_ping()?.is_not_ready() {
             return Ok(AsyncSink::NotReady(msg));
         }
-        let s = serde_json::to_string(&msg).chain_err(
-            || "failed to serialize",
-        )?;
-        match self.inner.start_send(Message::Text(s))? {
-            AsyncSink::Ready => Ok(AsyncSink::Ready),
+        // Tungstenite queues up everything it's given, so hold off once a
+        // slow client has too much waiting for it.
+        if self.max_send_queue.map_or(false, |max| self.unflushed >= max) {
+            if self.poll_complete()?.is_not_ready() {
+                return Ok(AsyncSink::NotReady(msg));
+            }
+        }
+        let frame = match self.encoding {
+            Encoding::Json => {
+                let s = serde_json::to_string(&msg).chain_err(
+                    || "failed to serialize",
+                )?;
+                Message::Text(s)
+            }
+            Encoding::MessagePack => Message::Binary(msg.to_msgpack()?),
+        };
+        match self.inner.start_send(frame)? {
+            AsyncSink::Ready => {
+                self.unflushed += 1;
+                Ok(AsyncSink::Ready)
+            }
             AsyncSink::NotReady(_) => Ok(AsyncSink::NotReady(msg)),
         }
     }
 
     fn poll_complete(&mut self) -> Poll<(), Error> {
         try_ready!(self.send_ping());
-        Ok(self.inner.poll_complete()?)
+        try_ready!(self.inner.poll_complete());
+        self.unflushed = 0;
+        Ok(Async::Ready(()))
     }
 
     fn close(&mut self) -> Poll<(), Error> {
@@ -769,3 +1307,53 @@ fn write_status(socket: WebpushIo) -> MyFuture<()> {
             .chain_err(|| "failed to write status response"),
     )
 }
+
+fn write_rejection(socket: WebpushIo, rejection: Rejection) -> MyFuture<()> {
+    let data = format!("{}\n", rejection.reason());
+    let data = format!("\
+        HTTP/1.1 {status}\r\n\
+        Server: webpush\r\n\
+        Date: {date}\r\n\
+        Sec-WebSocket-Version: 13\r\n\
+        Connection: close\r\n\
+        Content-Length: {len}\r\n\
+        \r\n\
+        {data}\
+    ",
+        status = rejection.status(),
+        date = time::at(time::get_time()).rfc822(),
+        len = data.len(),
+        data = data,
+    );
+    Box::new(
+        tokio_io::io::write_all(socket, data.into_bytes())
+            .map(|_| ())
+            .chain_err(|| "failed to write handshake rejection"),
+    )
+}
+
+fn write_metrics(socket: WebpushIo, prometheus: Option<&Prometheus>) -> MyFuture<()> {
+    let (status, data) = match prometheus {
+        Some(prometheus) => ("200 Ok", prometheus.render()),
+        None => ("404 Not Found", String::new()),
+    };
+    let data = format!("\
+        HTTP/1.1 {status}\r\n\
+        Server: webpush\r\n\
+        Date: {date}\r\n\
+        Content-Type: text/plain; version=0.0.4\r\n\
+        Content-Length: {len}\r\n\
+        \r\n\
+        {data}\
+    ",
+        status = status,
+        date = time::at(time::get_time()).rfc822(),
+        len = data.len(),
+        data = data,
+    );
+    Box::new(
+        tokio_io::io::write_all(socket, data.into_bytes())
+            .map(|_| ())
+            .chain_err(|| "failed to write metrics response"),
+    )
+}
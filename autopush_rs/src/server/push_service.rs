@@ -0,0 +1,323 @@
+//! RFC 8030 user agent interface
+//!
+//! Besides the websocket protocol, user agents can use a subset of the
+//! resources of RFC 8030 on the websocket port, once `push_service_key` is
+//! configured:
+//!
+//! * `POST /subscribe` creates a new user with a single channel, answering
+//!   `201 Created` with the subscription resource as `Location` and the push
+//!   resource (the channel's endpoint) as a `Link` with
+//!   `rel="urn:ietf:params:push"`. As this creates users, it's only served to
+//!   requests with an `Authorization: Bearer <push_service_key>` header,
+//!   others getting a `401`.
+//! * `GET` on the subscription resource lists the stored messages, each with
+//!   the push message resource it can be acknowledged through.
+//! * `DELETE` on a push message resource acknowledges it, deleting it from
+//!   storage.
+//!
+//! A subscription resource is `/subscription/<token>`, the token being issued
+//! by Python encrypted with the server's key. Knowing a uaid is therefore not
+//! enough to get at its messages. The user's message table is looked up from
+//! the user record rather than taken from the request, and push message
+//! resources (`/message/<token>/<version>`) only delete messages of the
+//! subscription's own user.
+//!
+//! This isn't the whole of RFC 8030, as there's no HTTP/2 in this server's
+//! stack:
+//!
+//! * Messages are delivered by polling the subscription resource over
+//!   HTTP/1.1, which answers with a JSON body of the stored messages (a
+//!   format of our own) rather than with HTTP/2 server pushes. At most
+//!   `MAX_MESSAGES` are listed at once, the rest following once those have
+//!   been acknowledged.
+//! * There are no receipt subscriptions (`urn:ietf:params:push:receipt`).
+//!   Application servers wanting receipts use the `Push-Receipt` header
+//!   instead, as for websocket clients.
+//!
+//! Everything here is backed by the same calls into Python as websocket
+//! clients use.
+
+use std::rc::Rc;
+
+use futures::future::{self, Future, Loop};
+use openssl::memcmp;
+use serde_json;
+use time;
+use tokio_io;
+use uuid::Uuid;
+
+use call::RegisterResponse;
+use errors::*;
+use protocol::Notification;
+use server::{Server, ServerOptions};
+use server::webpush_io::WebpushIo;
+
+// Most messages listed by a subscription resource at once
+const MAX_MESSAGES: usize = 100;
+
+/// A request for one of the RFC 8030 resources
+pub enum PushRequest {
+    Subscribe,
+    /// `POST /subscribe` without the right `push_service_key`
+    Unauthorized,
+    /// `GET` on the subscription with the given token
+    Receive(String),
+    /// `DELETE` on a stored message of a subscription, by its version
+    Acknowledge(String, String),
+    NotFound,
+}
+
+impl PushRequest {
+    /// Whether `path` is one of ours, to be passed on to `parse`. None of
+    /// them are without a `push_service_key`.
+    pub fn handles(opts: &ServerOptions, path: &str) -> bool {
+        opts.push_service_key.is_some() &&
+            (path == "/subscribe" || path.starts_with("/subscription/") ||
+                 path.starts_with("/message/"))
+    }
+
+    pub fn parse(
+        opts: &ServerOptions,
+        method: &str,
+        path: &str,
+        authorization: Option<&str>,
+    ) -> PushRequest {
+        let parts = path.split('/').skip(1).collect::<Vec<_>>();
+        let ret = match (method, parts.len()) {
+            ("POST", 1) if parts[0] == "subscribe" => {
+                if authorized(opts, authorization) {
+                    Some(PushRequest::Subscribe)
+                } else {
+                    Some(PushRequest::Unauthorized)
+                }
+            }
+            ("GET", 2) if parts[0] == "subscription" && is_token(parts[1]) => {
+                Some(PushRequest::Receive(parts[1].to_string()))
+            }
+            ("DELETE", 3) if parts[0] == "message" && is_token(parts[1]) && is_token(parts[2]) => {
+                Some(PushRequest::Acknowledge(parts[1].to_string(), parts[2].to_string()))
+            }
+            _ => None,
+        };
+        ret.unwrap_or(PushRequest::NotFound)
+    }
+}
+
+// Whether `authorization` is the bearer token of `push_service_key`
+fn authorized(opts: &ServerOptions, authorization: Option<&str>) -> bool {
+    let key = match opts.push_service_key {
+        Some(ref key) => key,
+        None => return false,
+    };
+    let token = match authorization.map(str::trim) {
+        Some(value) if value.len() > 7 && value.as_bytes()[..7].eq_ignore_ascii_case(b"bearer ") => {
+            value[7..].trim()
+        }
+        _ => return false,
+    };
+    // Compared in constant time, so the key can't be guessed byte by byte
+    token.len() == key.len() && memcmp::eq(token.as_bytes(), key.as_bytes())
+}
+
+// Whether `s` could be one of our tokens, which are all URL safe base64. Only
+// Python can tell whether it's valid.
+fn is_token(s: &str) -> bool {
+    !s.is_empty() &&
+        s.chars().all(|c| match c {
+            'a'...'z' | 'A'...'Z' | '0'...'9' | '-' | '_' | '=' => true,
+            _ => false,
+        })
+}
+
+/// Serves `req`, resolving once the response has been written.
+pub fn serve(srv: &Rc<Server>, io: WebpushIo, req: PushRequest) -> MyFuture<()> {
+    let response = match req {
+        PushRequest::Subscribe => subscribe(srv),
+        PushRequest::Unauthorized => {
+            let response = Response::new(401).header("WWW-Authenticate", "Bearer".to_string());
+            Box::new(future::ok(response))
+        }
+        PushRequest::Receive(subscription) => receive(srv, subscription),
+        PushRequest::Acknowledge(subscription, version) => {
+            acknowledge(srv, subscription, version)
+        }
+        PushRequest::NotFound => Box::new(future::ok(Response::new(404))),
+    };
+    Box::new(response.then(move |res| {
+        let response = res.unwrap_or_else(|e| {
+            debug!("Push service request failed: {}", e);
+            Response::new(500)
+        });
+        tokio_io::io::write_all(io, response.into_bytes())
+            .map(|_| ())
+            .chain_err(|| "failed to write push service response")
+    }))
+}
+
+fn subscribe(srv: &Rc<Server>) -> MyFuture<Response> {
+    let srv = srv.clone();
+    let connected_at = time::precise_time_ns() / 1000;
+    let hello = srv.hello(&connected_at, None);
+    Box::new(hello.and_then(move |hello| -> MyFuture<Response> {
+        // No uaid means the user record couldn't be written
+        let uaid = match hello.uaid {
+            Some(uaid) => uaid,
+            None => return Box::new(future::ok(Response::new(503))),
+        };
+        let register = srv.register(
+            uaid.simple().to_string(),
+            hello.message_month,
+            Uuid::new_v4().hyphenated().to_string(),
+            None,
+        );
+        let srv = srv.clone();
+        Box::new(register.and_then(move |response| -> MyFuture<Response> {
+            let endpoint = match response {
+                RegisterResponse::Success { endpoint } => endpoint,
+                RegisterResponse::Error { error_msg, status, .. } => {
+                    debug!("Push service subscribe failed"; "error" => error_msg);
+                    return Box::new(future::ok(Response::new(status)));
+                }
+            };
+            Box::new(srv.mint_subscription(&uaid).map(move |minted| {
+                Response::new(201)
+                    .header("Location", format!("/subscription/{}", minted.subscription))
+                    .header("Link", format!("<{}>; rel=\"urn:ietf:params:push\"", endpoint))
+            }))
+        }))
+    }))
+}
+
+fn receive(srv: &Rc<Server>, subscription: String) -> MyFuture<Response> {
+    let srv = srv.clone();
+    let resolve = srv.resolve_subscription(subscription.clone());
+    Box::new(resolve.and_then(move |resolved| -> MyFuture<Response> {
+        let (uaid, month) = match (resolved.uaid, resolved.message_month) {
+            (Some(uaid), Some(month)) => (uaid, month),
+            _ => return Box::new(future::ok(Response::new(404))),
+        };
+        let fetch = fetch_messages(&srv, uaid.simple().to_string(), month);
+        Box::new(fetch.and_then(move |messages| {
+            let messages = messages
+                .into_iter()
+                .map(|notif| {
+                    let location = format!("/message/{}/{}", subscription, notif.version);
+                    let mut message = serde_json::to_value(&notif)?;
+                    message["location"] = location.into();
+                    Ok(message)
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let body = serde_json::to_string(&json!({ "messages": messages }))?;
+            Ok(Response::new(200)
+                .header("Content-Type", "application/json".to_string())
+                .body(body))
+        }))
+    }))
+}
+
+/// Fetches up to `MAX_MESSAGES` of a user's stored messages, topic messages
+/// first, paging through storage as websocket clients do.
+fn fetch_messages(srv: &Rc<Server>, uaid: String, month: String) -> MyFuture<Vec<Notification>> {
+    let srv = srv.clone();
+    let start = (Vec::new(), true, None);
+    let fetch = future::loop_fn(start, move |(mut messages, include_topic, timestamp)| {
+        let check = srv.check_storage(uaid.clone(), month.clone(), include_topic, timestamp);
+        check.map(move |response| {
+            let fetched = response.messages.len();
+            messages.extend(response.messages);
+            // Topic messages come in a single page, after which timestamped
+            // ones are paged through from where they left off
+            if fetched > 0 && messages.len() < MAX_MESSAGES &&
+                (response.include_topic || response.timestamp.is_some())
+            {
+                Loop::Continue((messages, false, response.timestamp))
+            } else {
+                messages.truncate(MAX_MESSAGES);
+                Loop::Break(messages)
+            }
+        })
+    });
+    Box::new(fetch)
+}
+
+fn acknowledge(srv: &Rc<Server>, subscription: String, version: String) -> MyFuture<Response> {
+    // Python checks the message is the subscription's own, and deletes it
+    // straight off its version without fetching anything
+    Box::new(srv.ack_message(subscription, version).map(|response| {
+        Response::new(if response.deleted { 204 } else { 404 })
+    }))
+}
+
+struct Response {
+    status: u32,
+    headers: Vec<(&'static str, String)>,
+    body: String,
+}
+
+impl Response {
+    fn new(status: u32) -> Response {
+        Response {
+            status: status,
+            headers: Vec::new(),
+            body: String::new(),
+        }
+    }
+
+    fn header(mut self, name: &'static str, value: String) -> Response {
+        self.headers.push((name, value));
+        self
+    }
+
+    fn body(mut self, body: String) -> Response {
+        self.body = body;
+        self
+    }
+
+    fn into_bytes(self) -> Vec<u8> {
+        let reason = reason_phrase(self.status);
+        let mut headers = String::new();
+        for (name, value) in self.headers {
+            headers.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        format!("\
+            HTTP/1.1 {status} {reason}\r\n\
+            Server: webpush\r\n\
+            Date: {date}\r\n\
+            {headers}\
+            Content-Length: {len}\r\n\
+            \r\n\
+            {body}\
+        ",
+            status = self.status,
+            reason = reason,
+            date = time::at(time::get_time()).rfc822(),
+            headers = headers,
+            len = self.body.len(),
+            body = self.body,
+        ).into_bytes()
+    }
+}
+
+/// The standard reason phrase of `status`
+fn reason_phrase(status: u32) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        202 => "Accepted",
+        204 => "No Content",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        409 => "Conflict",
+        410 => "Gone",
+        413 => "Payload Too Large",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        502 => "Bad Gateway",
+        503 => "Service Unavailable",
+        504 => "Gateway Timeout",
+        _ => "Unknown",
+    }
+}
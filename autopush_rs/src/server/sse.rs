@@ -0,0 +1,209 @@
+//! Server-Sent Events fallback transport
+//!
+//! Some proxies strip websocket upgrades, so clients can instead open a
+//! `GET /sse` request on the websocket port. That's answered with a
+//! `text/event-stream` whose first event (`event: session`) carries a session
+//! token, and every `ServerMessage` after that is sent as a `data:` event of
+//! its JSON. The client sends its `ClientMessage`s as the JSON body of
+//! `POST /sse/<token>` requests, answered with a 202 once they've been handed
+//! over to the session.
+//!
+//! The event stream and the posted messages are glued together by
+//! `SseTransport`, which is the `Stream`/`Sink` driven by the same `Client`
+//! state machine as websockets are. An event stream counts towards
+//! `max_connections` until its session is over, like a websocket does.
+//!
+//! Session tokens only live in this node's `sse_sessions`, posts reaching
+//! another node get a 404: deployments with several nodes need sticky
+//! sessions at their load balancer.
+
+use std::io::{self, Read, Write};
+use std::rc::Rc;
+use std::time::Instant;
+
+use futures::future;
+use futures::sync::{mpsc, oneshot};
+use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
+use serde_json;
+use time;
+use tokio_core::reactor::Timeout;
+use tokio_io::{self, AsyncWrite};
+use uuid::Uuid;
+
+use client::Client;
+use errors::*;
+use protocol::{ClientMessage, ServerMessage};
+use server::Server;
+use server::webpush_io::WebpushIo;
+use util::RcObject;
+
+// Events buffered for a client before sends wait on it to catch up
+const MAX_BUFFERED: usize = 64 * 1024;
+
+pub struct SseTransport {
+    srv: Rc<Server>,
+    token: Uuid,
+    io: WebpushIo,
+    rx: mpsc::UnboundedReceiver<ClientMessage>,
+    buf: Vec<u8>,
+    keepalive: Timeout,
+}
+
+/// Serves a `GET /sse` request, resolving once the session is over.
+pub fn stream(srv: &Rc<Server>, io: WebpushIo, user_agent: String, host: String) -> MyFuture<()> {
+    let keepalive = match Timeout::new(srv.opts.auto_ping_interval, &srv.handle) {
+        Ok(timeout) => timeout,
+        Err(e) => return Box::new(future::err(e.into())),
+    };
+    let token = Uuid::new_v4();
+    let (tx, rx) = mpsc::unbounded();
+    srv.sse_sessions.borrow_mut().insert(token, tx);
+    debug!("Opened SSE session"; "token" => token.simple().to_string());
+
+    let head = format!("\
+        HTTP/1.1 200 Ok\r\n\
+        Server: webpush\r\n\
+        Date: {date}\r\n\
+        Content-Type: text/event-stream\r\n\
+        Cache-Control: no-cache\r\n\
+        X-Accel-Buffering: no\r\n\
+        \r\n\
+        event: session\n\
+        data: {token}\n\n\
+    ",
+        date = time::at(time::get_time()).rfc822(),
+        token = token.simple(),
+    );
+    let transport = RcObject::new(SseTransport {
+        srv: srv.clone(),
+        token: token,
+        io: io,
+        rx: rx,
+        buf: head.into_bytes(),
+        keepalive: keepalive,
+    });
+
+    let (uatx, uarx) = oneshot::channel();
+    drop(uatx.send(user_agent));
+    let client = Client::new(transport.clone(), srv, uarx, host);
+    Box::new(client.and_then(move |()| {
+        let mut transport = transport;
+        future::poll_fn(move || transport.close())
+    }))
+}
+
+/// Serves a `POST /sse/<token>`, handing its message over to the session.
+///
+/// A `body` of `None` means it was larger than `max_message_size`, or the
+/// cap on posts that applies whether or not that is set.
+pub fn post(srv: &Rc<Server>, io: WebpushIo, token: &str, body: Option<Vec<u8>>) -> MyFuture<()> {
+    let status = match body {
+        None => "413 Payload Too Large",
+        Some(body) => {
+            let sessions = srv.sse_sessions.borrow();
+            let session = Uuid::parse_str(token).ok().and_then(|t| sessions.get(&t));
+            match (session, serde_json::from_slice::<ClientMessage>(&body)) {
+                (None, _) => "404 Not Found",
+                (Some(_), Err(_)) => "400 Bad Request",
+                (Some(tx), Ok(msg)) => match tx.unbounded_send(msg) {
+                    Ok(()) => "202 Accepted",
+                    Err(_) => "404 Not Found",
+                },
+            }
+        }
+    };
+    let data = format!("\
+        HTTP/1.1 {status}\r\n\
+        Server: webpush\r\n\
+        Date: {date}\r\n\
+        Content-Length: 0\r\n\
+        \r\n\
+    ",
+        status = status,
+        date = time::at(time::get_time()).rfc822(),
+    );
+    Box::new(
+        tokio_io::io::write_all(io, data.into_bytes())
+            .map(|_| ())
+            .chain_err(|| "failed to write sse post response"),
+    )
+}
+
+impl Stream for SseTransport {
+    type Item = ClientMessage;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<ClientMessage>, Error> {
+        // Proxies tend to cut off streams that look idle, so send a comment
+        // every `auto_ping_interval`. Failing to write one is also how we
+        // find out about clients that went away.
+        if self.keepalive.poll()?.is_ready() {
+            self.buf.extend_from_slice(b": keepalive\n\n");
+            let at = Instant::now() + self.srv.opts.auto_ping_interval;
+            self.keepalive.reset(at);
+            self.keepalive.poll()?;
+        }
+        if !self.buf.is_empty() {
+            self.poll_complete()?;
+        }
+
+        // Nothing's expected from the client on this connection besides the
+        // request itself, so reading is only to notice it getting closed.
+        let mut scratch = [0; 256];
+        loop {
+            match self.io.read(&mut scratch) {
+                Ok(0) => return Ok(Async::Ready(None)),
+                Ok(_) => {}
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        self.rx.poll().map_err(|()| Error::from("sse session closed"))
+    }
+}
+
+impl Sink for SseTransport {
+    type SinkItem = ServerMessage;
+    type SinkError = Error;
+
+    fn start_send(&mut self, msg: ServerMessage) -> StartSend<ServerMessage, Error> {
+        if self.buf.len() >= MAX_BUFFERED && self.poll_complete()?.is_not_ready() {
+            return Ok(AsyncSink::NotReady(msg));
+        }
+        let s = serde_json::to_string(&msg).chain_err(|| "failed to serialize")?;
+        self.buf.extend_from_slice(b"data: ");
+        self.buf.extend_from_slice(s.as_bytes());
+        self.buf.extend_from_slice(b"\n\n");
+        Ok(AsyncSink::Ready)
+    }
+
+    fn poll_complete(&mut self) -> Poll<(), Error> {
+        while !self.buf.is_empty() {
+            match self.io.write(&self.buf) {
+                Ok(0) => return Err("failed to write sse event".into()),
+                Ok(n) => drop(self.buf.drain(..n)),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    return Ok(Async::NotReady)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        match self.io.flush() {
+            Ok(()) => Ok(Async::Ready(())),
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => Ok(Async::NotReady),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn close(&mut self) -> Poll<(), Error> {
+        try_ready!(self.poll_complete());
+        Ok(self.io.shutdown()?)
+    }
+}
+
+impl Drop for SseTransport {
+    fn drop(&mut self) {
+        self.srv.sse_sessions.borrow_mut().remove(&self.token);
+    }
+}
@@ -0,0 +1,156 @@
+//! Snapshots of what a running server is up to
+//!
+//! Python's health and diagnostic code can't see into the Rust half of a
+//! connection node, so this keeps a few running tallies on top of what the
+//! `Server` already tracks. They're gathered up into a `Snapshot` on the
+//! tokio thread whenever Python asks through `autopush_server_stats`, and
+//! handed over as JSON.
+
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+
+use serde_json;
+
+use call::Priority;
+use rt::{self, AutopushError};
+use server::Server;
+
+pub struct Stats {
+    // Number of clients in each `ClientState`, by its name
+    client_states: RefCell<HashMap<&'static str, u32>>,
+    // Connections past the TLS handshake (if any), by whether it was TLS
+    tls_connections: Cell<u32>,
+    plaintext_connections: Cell<u32>,
+    // Bytes read from and written to clients, after TLS decryption
+    bytes_in: Cell<u64>,
+    bytes_out: Cell<u64>,
+}
+
+#[derive(Serialize)]
+pub struct Snapshot {
+    open_connections: u32,
+    registered_uaids: usize,
+    client_states: BTreeMap<&'static str, u32>,
+    queue_depth: BTreeMap<&'static str, usize>,
+    tls_connections: u32,
+    plaintext_connections: u32,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl Stats {
+    pub fn new() -> Stats {
+        Stats {
+            client_states: RefCell::new(HashMap::new()),
+            tls_connections: Cell::new(0),
+            plaintext_connections: Cell::new(0),
+            bytes_in: Cell::new(0),
+            bytes_out: Cell::new(0),
+        }
+    }
+
+    pub fn client_entered(&self, state: &'static str) {
+        *self.client_states.borrow_mut().entry(state).or_insert(0) += 1;
+    }
+
+    pub fn client_left(&self, state: &'static str) {
+        let mut states = self.client_states.borrow_mut();
+        let done = match states.get_mut(state) {
+            Some(count) => {
+                *count -= 1;
+                *count == 0
+            }
+            None => false,
+        };
+        if done {
+            states.remove(state);
+        }
+    }
+
+    pub fn connection_opened(&self, tls: bool) {
+        let count = self.connections(tls);
+        count.set(count.get() + 1);
+    }
+
+    pub fn connection_closed(&self, tls: bool) {
+        let count = self.connections(tls);
+        count.set(count.get() - 1);
+    }
+
+    fn connections(&self, tls: bool) -> &Cell<u32> {
+        if tls {
+            &self.tls_connections
+        } else {
+            &self.plaintext_connections
+        }
+    }
+
+    pub fn read(&self, n: usize) {
+        self.bytes_in.set(self.bytes_in.get() + n as u64);
+    }
+
+    pub fn wrote(&self, n: usize) {
+        self.bytes_out.set(self.bytes_out.get() + n as u64);
+    }
+}
+
+impl Server {
+    pub fn stats_snapshot(&self) -> Snapshot {
+        let mut queue_depth = BTreeMap::new();
+        for priority in &[Priority::Interactive, Priority::Background] {
+            queue_depth.insert(priority.name(), self.tx.depth(*priority));
+        }
+        Snapshot {
+            open_connections: self.open_connections.get(),
+            registered_uaids: self.uaids.borrow().len(),
+            client_states: self.stats
+                .client_states
+                .borrow()
+                .iter()
+                .map(|(state, count)| (*state, *count))
+                .collect(),
+            queue_depth: queue_depth,
+            tls_connections: self.stats.tls_connections.get(),
+            plaintext_connections: self.stats.plaintext_connections.get(),
+            bytes_in: self.stats.bytes_in.get(),
+            bytes_out: self.stats.bytes_out.get(),
+        }
+    }
+}
+
+/// A `Snapshot` rendered as JSON, as returned by `autopush_server_stats`
+#[repr(C)]
+pub struct AutopushServerStats {
+    json: String,
+}
+
+impl AutopushServerStats {
+    pub fn new(snapshot: &Snapshot) -> AutopushServerStats {
+        AutopushServerStats {
+            json: serde_json::to_string(snapshot).expect("failed to serialize stats"),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn autopush_server_stats_ptr(
+    stats: *mut AutopushServerStats,
+    err: &mut AutopushError,
+) -> *const u8 {
+    rt::catch(err, || unsafe { (*stats).json.as_ptr() })
+}
+
+#[no_mangle]
+pub extern "C" fn autopush_server_stats_len(
+    stats: *mut AutopushServerStats,
+    err: &mut AutopushError,
+) -> usize {
+    rt::catch(err, || unsafe { (&*stats).json.len() })
+}
+
+#[no_mangle]
+pub extern "C" fn autopush_server_stats_free(stats: *mut AutopushServerStats) {
+    rt::abort_on_panic(|| unsafe {
+        Box::from_raw(stats);
+    })
+}
@@ -4,66 +4,261 @@
 //! `AsyncRead`/`AsyncWrite` stream, so this provides a `MaybeTlsStream` type
 //! which dispatches at runtime whether it's a plaintext or TLS stream after a
 //! connection is established.
+//!
+//! Multiple certificates can be served off the same listener. The
+//! `ssl_key`/`ssl_cert` pair is the default, and each of the `ssl_sni_certs`
+//! is picked instead when the client's SNI hostname matches one of the names
+//! (subject alt names or common name) in its certificate.
+//!
+//! The internal router listener can separately be configured for TLS through
+//! `RouterTls`, optionally requiring peers to present a client certificate
+//! that's either signed by a configured CA or whose SHA-256 fingerprint is
+//! explicitly allowed.
 
+use std::collections::HashMap;
 use std::fs::File;
 use std::io::{self, Read, Write};
+use std::mem;
 use std::path::Path;
 use std::rc::Rc;
 
 use futures::future;
 use futures::{Poll, Future};
 use openssl::dh::Dh;
-use openssl::pkey::PKey;
-use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslMethod};
-use openssl::ssl::SSL_MODE_RELEASE_BUFFERS;
-use openssl::x509::X509;
+use openssl::hash::MessageDigest;
+use openssl::nid;
+use openssl::pkey::{PKey, PKeyRef};
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslContext, SslContextBuilder, SslMethod};
+use openssl::ssl::{SniError, SslRef, SSL_MODE_RELEASE_BUFFERS, SSL_VERIFY_PEER};
+use openssl::x509::{X509, X509Ref};
 use tokio_core::net::TcpStream;
 use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_openssl::{SslStream, SslAcceptorExt};
 
-use server::{Server, ServerOptions};
+use server::{CertConfig, Server, ServerOptions};
 use errors::*;
 
 /// Creates an `SslAcceptor`, if needed, ready to accept TLS connections.
 ///
 /// This method is called early on when the server is created and the
 /// `SslAcceptor` type is stored globally in the `Server` structure, later used
-/// to process all accepted TCP sockets.
-pub fn configure(opts: &ServerOptions) -> Option<SslAcceptor> {
+/// to process all accepted TCP sockets. It's called again whenever the TLS
+/// configuration is reloaded, so any problem with the key, certificates or
+/// their files is returned as an error rather than a panic.
+pub fn configure(opts: &ServerOptions) -> Result<Option<SslAcceptor>> {
     let key = match opts.ssl_key {
-        Some(ref key) => read(key),
-        None => return None,
+        Some(ref key) => read(key)?,
+        None => return Ok(None),
+    };
+    let key = PKey::private_key_from_pem(&key).chain_err(|| "failed to create private key")?;
+    let cert = read(opts.ssl_cert.as_ref().ok_or("ssl_cert not configured")?)?;
+    let cert = X509::from_pem(&cert).chain_err(|| "failed to create certificate")?;
+
+    let chain = match opts.ssl_chain {
+        Some(ref chain) => read_chain(chain)?,
+        None => Vec::new(),
     };
-    let key = PKey::private_key_from_pem(&key)
-        .expect("failed to create private key");
-    let cert = read(opts.ssl_cert.as_ref().expect("ssl_cert not configured"));
-    let cert = X509::from_pem(&cert).expect("failed to create certificate");
-
-    let mut builder = SslAcceptorBuilder::mozilla_intermediate(
-        SslMethod::tls(),
-        &key,
-        &cert,
-        Vec::<X509>::new(),
-    ).expect("failed to create ssl acceptor builder");
+
+    let mut builder = acceptor_builder(opts, &key, &cert, chain)?;
+
+    if !opts.ssl_sni_certs.is_empty() {
+        let mut contexts = HashMap::new();
+        for config in opts.ssl_sni_certs.iter() {
+            let (names, context) = sni_context(opts, config)
+                .chain_err(|| format!("invalid SNI certificate {:?}", config.cert))?;
+            for name in names {
+                contexts.insert(name.to_lowercase(), context.clone());
+            }
+        }
+        builder.builder_mut().set_servername_callback(move |ssl| {
+            let context = match ssl.servername().and_then(|name| lookup(&contexts, name)) {
+                Some(context) => context,
+                // Unknown or missing SNI, carry on with the default certificate
+                None => return Ok(()),
+            };
+            ssl.set_ssl_context(context).map_err(|_| SniError::NoAck)
+        });
+    }
+
+    return Ok(Some(builder.build()));
+
+    // Exact hostnames win over wildcards, which only cover a single label
+    fn lookup<'a>(contexts: &'a HashMap<String, SslContext>, name: &str) -> Option<&'a SslContext> {
+        let name = name.to_lowercase();
+        contexts.get(&name).or_else(|| {
+            let dot = name.find('.')?;
+            contexts.get(&format!("*{}", &name[dot..]))
+        })
+    }
+}
+
+/// TLS configuration of the internal router listener
+pub struct RouterTls {
+    acceptor: SslAcceptor,
+    verify_ca: bool,
+    // Allowed client certificate fingerprints, mapped to a name for logging
+    client_certs: HashMap<String, String>,
+}
+
+impl RouterTls {
+    /// Creates the router's `RouterTls`, if it's configured with a key.
+    pub fn configure(opts: &ServerOptions) -> Result<Option<RouterTls>> {
+        let key = match opts.router_ssl_key {
+            Some(ref key) => read(key)?,
+            None => return Ok(None),
+        };
+        let key = PKey::private_key_from_pem(&key).chain_err(|| "failed to create private key")?;
+        let cert = read(opts.router_ssl_cert.as_ref().ok_or("router_ssl_cert not configured")?)?;
+        let cert = X509::from_pem(&cert).chain_err(|| "failed to create certificate")?;
+
+        let mut builder = SslAcceptorBuilder::mozilla_intermediate(
+            SslMethod::tls(),
+            &key,
+            &cert,
+            Vec::<X509>::new(),
+        ).chain_err(|| "failed to create router ssl acceptor builder")?;
+        if let Some(dh_param) = opts.ssl_dh_param.as_ref() {
+            let dh_param = Dh::from_pem(&read(dh_param)?).chain_err(|| "failed to create dh")?;
+            builder
+                .builder_mut()
+                .set_tmp_dh(&dh_param)
+                .chain_err(|| "failed to set dh_param")?;
+        }
+        builder.builder_mut().set_mode(SSL_MODE_RELEASE_BUFFERS);
+
+        let verify_ca = opts.router_ssl_ca.is_some();
+        if let Some(ca) = opts.router_ssl_ca.as_ref() {
+            builder
+                .builder_mut()
+                .set_ca_file(ca)
+                .chain_err(|| format!("failed to load CA {:?}", ca))?;
+        }
+        if verify_ca || !opts.router_client_certs.is_empty() {
+            // Ask for a client certificate but let the handshake through
+            // regardless, `authorized` decides per connection so the peer
+            // gets a proper 401 instead of a failed handshake.
+            builder
+                .builder_mut()
+                .set_verify_callback(SSL_VERIFY_PEER, |_preverify_ok, _ctx| true);
+        }
+
+        Ok(Some(RouterTls {
+            acceptor: builder.build(),
+            verify_ca: verify_ca,
+            client_certs: opts.router_client_certs.clone(),
+        }))
+    }
+
+    /// Whether the peer on this TLS session may push notifications
+    fn authorized(&self, ssl: &SslRef) -> bool {
+        if !self.verify_ca && self.client_certs.is_empty() {
+            return true;
+        }
+        let cert = match ssl.peer_certificate() {
+            Some(cert) => cert,
+            None => return false,
+        };
+        if self.verify_ca && ssl.verify_result().is_none() {
+            return true;
+        }
+        let fingerprint = match cert.fingerprint(MessageDigest::sha256()) {
+            Ok(digest) => digest
+                .iter()
+                .map(|b| format!("{:02X}", b))
+                .collect::<Vec<_>>()
+                .join(":"),
+            Err(_) => return false,
+        };
+        match self.client_certs.get(&fingerprint) {
+            Some(name) => {
+                debug!("Router client certificate allowed"; "name" => name.as_str());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Sets up Mozilla's intermediate profile for `key` and `cert`, with the
+/// configured DH parameters, as used for the default certificate and the SNI
+/// ones alike.
+fn acceptor_builder(
+    opts: &ServerOptions,
+    key: &PKeyRef,
+    cert: &X509Ref,
+    chain: Vec<X509>,
+) -> Result<SslAcceptorBuilder> {
+    // Also checks that the private key matches the certificate
+    let mut builder = SslAcceptorBuilder::mozilla_intermediate(SslMethod::tls(), key, cert, chain)
+        .chain_err(|| "failed to create ssl acceptor builder")?;
 
     if let Some(dh_param) = opts.ssl_dh_param.as_ref() {
-        let dh_param = Dh::from_pem(&read(dh_param)).expect("failed to create dh");
-        builder.builder_mut().set_tmp_dh(&dh_param).expect("failed to set dh_param");
+        let dh_param = Dh::from_pem(&read(dh_param)?).chain_err(|| "failed to create dh")?;
+        builder
+            .builder_mut()
+            .set_tmp_dh(&dh_param)
+            .chain_err(|| "failed to set dh_param")?;
     }
 
     // Should help reduce peak memory consumption for idle connections
     builder.builder_mut().set_mode(SSL_MODE_RELEASE_BUFFERS);
+    Ok(builder)
+}
 
-    return Some(builder.build());
+/// Builds the `SslContext` swapped in for a connection whose SNI hostname
+/// matches `config`, returning it along with the hostnames it serves.
+///
+/// Swapping contexts replaces the DH and ECDH parameters along with the
+/// certificate, so it's set up just like the default one.
+fn sni_context(opts: &ServerOptions, config: &CertConfig) -> Result<(Vec<String>, SslContext)> {
+    let key = PKey::private_key_from_pem(&read(&config.key)?)
+        .chain_err(|| "failed to create private key")?;
+    let cert = X509::from_pem(&read(&config.cert)?).chain_err(|| "failed to create certificate")?;
+    let chain = match config.chain {
+        Some(ref chain) => read_chain(chain)?,
+        None => Vec::new(),
+    };
 
-    fn read(path: &Path) -> Vec<u8> {
-        let mut out = Vec::new();
-        File::open(path)
-            .expect(&format!("failed to open {:?}", path))
-            .read_to_end(&mut out)
-            .expect(&format!("failed to read {:?}", path));
-        return out
+    let names = hostnames(&cert);
+    if names.is_empty() {
+        return Err("no hostnames found in certificate".into());
     }
+
+    let mut builder = acceptor_builder(opts, &key, &cert, chain)?;
+    // An `SslAcceptorBuilder` only lends out its context, so it's taken out
+    // from under it
+    let empty = SslContextBuilder::new(SslMethod::tls())
+        .chain_err(|| "failed to create ssl context builder")?;
+    let context = mem::replace(builder.builder_mut(), empty).build();
+    Ok((names, context))
+}
+
+/// All DNS subject alt names of `cert`, falling back to its common name
+fn hostnames(cert: &X509Ref) -> Vec<String> {
+    let mut names = Vec::new();
+    if let Some(alt_names) = cert.subject_alt_names() {
+        names.extend(alt_names.iter().filter_map(|n| n.dnsname()).map(String::from));
+    }
+    if names.is_empty() {
+        for entry in cert.subject_name().entries_by_nid(nid::COMMONNAME) {
+            if let Ok(name) = entry.data().as_utf8() {
+                names.push(name.to_string());
+            }
+        }
+    }
+    names
+}
+
+fn read_chain(path: &Path) -> Result<Vec<X509>> {
+    X509::stack_from_pem(&read(path)?).chain_err(|| format!("failed to read chain {:?}", path))
+}
+
+fn read(path: &Path) -> Result<Vec<u8>> {
+    let mut out = Vec::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut out))
+        .chain_err(|| format!("failed to read {:?}", path))?;
+    Ok(out)
 }
 
 /// Performs the TLS handshake, if necessary, for a socket.
@@ -75,7 +270,9 @@ pub fn configure(opts: &ServerOptions) -> Option<SslAcceptor> {
 pub fn accept(srv: &Rc<Server>, socket: TcpStream)
     -> MyFuture<MaybeTlsStream<TcpStream>>
 {
-    match srv.tls_acceptor {
+    // The acceptor may be swapped out by a reload later on, but handshakes
+    // already started here keep using the configuration they started with.
+    match *srv.tls_acceptor.borrow() {
         Some(ref acceptor) => {
             Box::new(acceptor.accept_async(socket)
                 .map(MaybeTlsStream::Tls)
@@ -85,11 +282,44 @@ pub fn accept(srv: &Rc<Server>, socket: TcpStream)
     }
 }
 
+/// Performs the TLS handshake, if necessary, for a socket accepted on the
+/// router listener.
+///
+/// Resolves to the stream along with whether its peer passed client
+/// certificate verification, which is always the case for plaintext or when
+/// no verification is configured.
+pub fn accept_router(srv: &Rc<Server>, socket: TcpStream)
+    -> MyFuture<(MaybeTlsStream<TcpStream>, bool)>
+{
+    match srv.router_tls {
+        Some(ref tls) => {
+            let srv = srv.clone();
+            Box::new(tls.acceptor.accept_async(socket)
+                .map(move |stream| {
+                    let tls = srv.router_tls.as_ref().unwrap();
+                    let authorized = tls.authorized(stream.get_ref().ssl());
+                    (MaybeTlsStream::Tls(stream), authorized)
+                })
+                .chain_err(|| "failed to accept router TLS socket"))
+        }
+        None => Box::new(future::ok((MaybeTlsStream::Plain(socket), true))),
+    }
+}
+
 pub enum MaybeTlsStream<T> {
     Plain(T),
     Tls(SslStream<T>),
 }
 
+impl<T> MaybeTlsStream<T> {
+    pub fn is_tls(&self) -> bool {
+        match *self {
+            MaybeTlsStream::Plain(_) => false,
+            MaybeTlsStream::Tls(_) => true,
+        }
+    }
+}
+
 impl<T: Read + Write> Read for MaybeTlsStream<T> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         match *self {
@@ -5,32 +5,119 @@
 //! After that, for tungstenite the websocket library, we'll want to replay the
 //! data we already read as there's no ability to pass this in currently. That
 //! means we'll parse headers twice, but alas!
+//!
+//! Tungstenite (0.5, without a `WebSocketConfig`) also has no limits on the
+//! size of frames or messages it'll buffer up, so for websocket requests
+//! that's enforced here too. Incoming bytes past the HTTP request are followed
+//! frame by frame, and once a frame or message goes over the
+//! configured limits the client is sent a 1009 (message too big) close frame
+//! and the connection errors out before tungstenite buffers any of it.
+//! Control frames are held to the protocol's 125 bytes here as well, as
+//! tungstenite only checks that after buffering the whole frame.
+//!
+//! Websockets that negotiated permessage-deflate also have their frames
+//! rewritten here, see the `deflate` module. What tungstenite writes is then
+//! buffered up until it's been compressed and written out.
 
+use std::cmp;
 use std::io::{self, Read, Write};
+use std::rc::Rc;
 
 use bytes::BytesMut;
-use futures::Poll;
+use cadence::prelude::*;
+use futures::{Async, Poll};
 use tokio_core::net::TcpStream;
 use tokio_io::{AsyncRead, AsyncWrite};
 
+use server::Server;
+use server::deflate::{Codec, Params};
 use server::tls::MaybeTlsStream;
 
+// Close frame with status 1009, as sent unmasked by a server
+const CLOSE_TOO_BIG: [u8; 4] = [0x88, 0x02, 0x03, 0xf1];
+
+// Largest payload the protocol allows a control frame
+const MAX_CONTROL_PAYLOAD: u64 = 125;
+
+// Compressed frames buffered up before writes wait on the client
+const MAX_ENCODED: usize = 64 * 1024;
+
 pub struct WebpushIo {
     tcp: MaybeTlsStream<TcpStream>,
     header_to_read: Option<BytesMut>,
+    limits: Option<Limits>,
+    deflate: Option<Deflated>,
+    srv: Rc<Server>,
+}
+
+// permessage-deflate state of a websocket that negotiated it
+struct Deflated {
+    codec: Codec,
+    // Frames rewritten for tungstenite to read
+    decoded: Vec<u8>,
+    // Frames rewritten to be written out
+    encoded: Vec<u8>,
+}
+
+// State for enforcing `max_frame_size`, `max_message_size` and the control
+// frame limit
+struct Limits {
+    srv: Rc<Server>,
+    // Bytes of the HTTP request still to be read before frames start
+    request_left: usize,
+    // How far into the "\r\n\r\n" ending the HTTP response we've written
+    response_end: usize,
+    incoming: FrameCursor,
+    outgoing: FrameCursor,
+    message_len: u64,
+    exceeded: bool,
 }
 
 impl WebpushIo {
-    pub fn new(tcp: MaybeTlsStream<TcpStream>, header: BytesMut) -> WebpushIo {
+    /// Wraps `tcp`, replaying `header` (of which the first `request_len`
+    /// bytes are the HTTP request) before reading anything more off it.
+    ///
+    /// Frame and message limits only apply to `websocket` requests, nothing
+    /// else is framed. Those always have their control frames checked, even
+    /// without any limits configured, and are compressed with `deflate` if
+    /// they negotiated it.
+    pub fn new(
+        tcp: MaybeTlsStream<TcpStream>,
+        header: BytesMut,
+        request_len: usize,
+        websocket: bool,
+        deflate: Option<Params>,
+        srv: &Rc<Server>,
+    ) -> WebpushIo {
+        // `header` is everything read off the connection so far
+        srv.stats.connection_opened(tcp.is_tls());
+        srv.stats.read(header.len());
         WebpushIo {
             tcp: tcp,
             header_to_read: Some(header),
+            limits: if websocket {
+                Some(Limits {
+                    srv: srv.clone(),
+                    request_left: request_len,
+                    response_end: 0,
+                    incoming: FrameCursor::new(),
+                    outgoing: FrameCursor::new(),
+                    message_len: 0,
+                    exceeded: false,
+                })
+            } else {
+                None
+            },
+            deflate: deflate.map(|params| Deflated {
+                codec: Codec::new(params),
+                decoded: Vec::new(),
+                encoded: Vec::new(),
+            }),
+            srv: srv.clone(),
         }
     }
-}
 
-impl Read for WebpushIo {
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    fn read_raw(&mut self, buf: &mut [u8]) -> io::Result<usize> {
         // Start off by replaying the bytes already read, and after that just
         // delegate everything to the internal `TcpStream`
         if let Some(ref mut header) = self.header_to_read {
@@ -41,18 +128,167 @@ impl Read for WebpushIo {
             }
         }
         self.header_to_read = None;
-        self.tcp.read(buf)
+        let n = self.tcp.read(buf)?;
+        self.srv.stats.read(n);
+        Ok(n)
+    }
+
+    fn read_deflated(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            {
+                let deflated = self.deflate.as_mut().unwrap();
+                // Keep compressed frames going out while tungstenite is
+                // waiting on the client
+                match deflated.drain(&mut self.tcp, &self.srv) {
+                    Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+                    other => other?,
+                }
+                if !deflated.decoded.is_empty() || buf.is_empty() {
+                    let n = cmp::min(buf.len(), deflated.decoded.len());
+                    buf[..n].copy_from_slice(&deflated.decoded[..n]);
+                    deflated.decoded.drain(..n);
+                    return Ok(n);
+                }
+            }
+
+            let n = self.read_raw(buf)?;
+            if n == 0 {
+                return Ok(0);
+            }
+            let ok = {
+                let limits = self.limits.as_mut().unwrap();
+                let deflated = self.deflate.as_mut().unwrap();
+                let request = cmp::min(limits.request_left, n);
+                deflated.decoded.extend_from_slice(&buf[..request]);
+                // Limits apply to messages both as sent and once inflated
+                let max_message = self.srv.opts.max_message_size.unwrap_or(u64::max_value());
+                limits.check_incoming(&buf[..n])
+                    && deflated.codec.incoming(&buf[request..n], &mut deflated.decoded, max_message)?
+            };
+            self.report_saved();
+            if !ok {
+                return Err(self.refuse());
+            }
+        }
+    }
+
+    // Gives up on a client over the limits, telling it why unless that'd
+    // land in the middle of a frame being written out
+    fn refuse(&mut self) -> io::Error {
+        let limits = self.limits.as_mut().unwrap();
+        limits.exceeded = true;
+        limits.srv.metrics.incr("ua.websocket.message_too_big").ok();
+        if limits.response_end == 4 {
+            match self.deflate {
+                // Only whole frames are ever buffered up to be written
+                Some(ref mut deflated) => {
+                    deflated.encoded.extend_from_slice(&CLOSE_TOO_BIG);
+                    drop(deflated.drain(&mut self.tcp, &self.srv));
+                }
+                None if limits.outgoing.at_boundary() => {
+                    drop(self.tcp.write(&CLOSE_TOO_BIG).and_then(|_| self.tcp.flush()));
+                }
+                None => {}
+            }
+        }
+        too_big()
+    }
+
+    // Counts the bytes compression saved since last time
+    fn report_saved(&mut self) {
+        if let Some(ref mut deflated) = self.deflate {
+            let (incoming, outgoing) = deflated.codec.take_saved();
+            for &(direction, saved) in [("in", incoming), ("out", outgoing)].iter() {
+                if saved != 0 {
+                    self.srv
+                        .metrics
+                        .count_with_tags("ua.websocket.deflate.saved", saved)
+                        .with_tag("direction", direction)
+                        .send()
+                        .ok();
+                }
+            }
+        }
+    }
+}
+
+impl Deflated {
+    // Writes out as much of `encoded` as the socket takes
+    fn drain(&mut self, tcp: &mut MaybeTlsStream<TcpStream>, srv: &Server) -> io::Result<()> {
+        while !self.encoded.is_empty() {
+            let n = tcp.write(&self.encoded)?;
+            if n == 0 {
+                return Err(io::ErrorKind::WriteZero.into());
+            }
+            srv.stats.wrote(n);
+            self.encoded.drain(..n);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for WebpushIo {
+    fn drop(&mut self) {
+        self.srv.stats.connection_closed(self.tcp.is_tls());
+    }
+}
+
+impl Read for WebpushIo {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.limits.as_ref().map_or(false, |l| l.exceeded) {
+            return Err(too_big());
+        }
+        if self.deflate.is_some() {
+            return self.read_deflated(buf);
+        }
+        let n = self.read_raw(buf)?;
+        let ok = match self.limits {
+            Some(ref mut limits) => limits.check_incoming(&buf[..n]),
+            None => true,
+        };
+        if ok {
+            Ok(n)
+        } else {
+            Err(self.refuse())
+        }
     }
 }
 
 // All `write` calls are routed through the `TcpStream` instance directly as we
-// don't buffer this at all.
+// don't buffer this at all, unless frames are compressed.
 impl Write for WebpushIo {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        self.tcp.write(buf)
+        let deflated = match self.deflate {
+            Some(ref mut deflated) => deflated,
+            None => {
+                let n = self.tcp.write(buf)?;
+                self.srv.stats.wrote(n);
+                if let Some(ref mut limits) = self.limits {
+                    limits.track_outgoing(&buf[..n]);
+                }
+                return Ok(n);
+            }
+        };
+
+        // Hold tungstenite back while the client isn't keeping up
+        if deflated.encoded.len() >= MAX_ENCODED {
+            deflated.drain(&mut self.tcp, &self.srv)?;
+        }
+        let response = self.limits.as_mut().unwrap().track_outgoing(buf);
+        deflated.encoded.extend_from_slice(&buf[..response]);
+        deflated.codec.outgoing(&buf[response..], &mut deflated.encoded)?;
+        match deflated.drain(&mut self.tcp, &self.srv) {
+            Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => {}
+            other => other?,
+        }
+        self.report_saved();
+        Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
+        if let Some(ref mut deflated) = self.deflate {
+            deflated.drain(&mut self.tcp, &self.srv)?;
+        }
         self.tcp.flush()
     }
 }
@@ -61,6 +297,305 @@ impl AsyncRead for WebpushIo {}
 
 impl AsyncWrite for WebpushIo {
     fn shutdown(&mut self) -> Poll<(), io::Error> {
+        if let Some(ref mut deflated) = self.deflate {
+            match deflated.drain(&mut self.tcp, &self.srv) {
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(Async::NotReady),
+                other => other?,
+            }
+        }
         AsyncWrite::shutdown(&mut self.tcp)
     }
 }
+
+impl Limits {
+    /// Follows incoming frames, returning whether they're all within limits
+    fn check_incoming(&mut self, data: &[u8]) -> bool {
+        let skip = cmp::min(self.request_left, data.len());
+        self.request_left -= skip;
+
+        let max_frame = self.srv.opts.max_frame_size.unwrap_or(u64::max_value());
+        let max_message = self.srv.opts.max_message_size.unwrap_or(u64::max_value());
+        let mut message_len = self.message_len;
+        let ok = self.incoming.advance(&data[skip..], |first, len| {
+            frame_within_limits(first, len, &mut message_len, max_frame, max_message)
+        });
+        self.message_len = message_len;
+        ok
+    }
+
+    /// Follows outgoing frames, returning how many bytes at the start of
+    /// `data` are still part of the HTTP response
+    fn track_outgoing(&mut self, data: &[u8]) -> usize {
+        let mut response = 0;
+        while self.response_end < 4 && response < data.len() {
+            self.response_end = match (self.response_end, data[response]) {
+                (0, b'\r') | (2, b'\r') => self.response_end + 1,
+                (1, b'\n') | (3, b'\n') => self.response_end + 1,
+                (_, b'\r') => 1,
+                _ => 0,
+            };
+            response += 1;
+        }
+        self.outgoing.advance(&data[response..], |_, _| true);
+        response
+    }
+}
+
+/// Whether an incoming frame, starting with `first` and carrying `len` bytes,
+/// keeps within the limits. `message_len` is the length of the message it
+/// belongs to so far, updated to include it.
+fn frame_within_limits(
+    first: u8,
+    len: u64,
+    message_len: &mut u64,
+    max_frame: u64,
+    max_message: u64,
+) -> bool {
+    if len > max_frame {
+        return false;
+    }
+    match first & 0x0f {
+        // Continuation of a fragmented message
+        0x0 => *message_len = message_len.saturating_add(len),
+        // Text or binary, starting a new message
+        0x1 | 0x2 => *message_len = len,
+        // Control frames sit between a message's frames, outside of it, but
+        // are held to the message limit all the same
+        _ => return len <= MAX_CONTROL_PAYLOAD && len <= max_message,
+    }
+    *message_len <= max_message
+}
+
+// Follows websocket frame boundaries in one direction of a byte stream
+pub struct FrameCursor {
+    header: [u8; 14],
+    have: usize,
+    remaining: u64,
+}
+
+/// A websocket frame's header
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Frame {
+    /// The first byte: FIN, RSV and opcode bits
+    pub first: u8,
+    pub len: u64,
+    pub mask: Option<[u8; 4]>,
+}
+
+/// What's next in a byte stream of frames
+pub enum Piece<'a> {
+    /// A frame's header, once all of it is in
+    Header(Frame),
+    /// Payload of the frame whose header came last
+    Payload(&'a [u8]),
+}
+
+impl FrameCursor {
+    pub fn new() -> FrameCursor {
+        FrameCursor {
+            header: [0; 14],
+            have: 0,
+            remaining: 0,
+        }
+    }
+
+    pub fn at_boundary(&self) -> bool {
+        self.have == 0 && self.remaining == 0
+    }
+
+    /// Feeds `data` through, calling `on_frame` with the first header byte
+    /// and payload length of each frame starting in it. Stops as soon as
+    /// `on_frame` returns false, returning false as well.
+    fn advance<F>(&mut self, mut data: &[u8], mut on_frame: F) -> bool
+    where
+        F: FnMut(u8, u64) -> bool,
+    {
+        while let Some(piece) = self.next(&mut data) {
+            if let Piece::Header(frame) = piece {
+                if !on_frame(frame.first, frame.len) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Takes the next header or run of payload off the front of `data`,
+    /// `None` once all of it has been taken (buffering any partial header).
+    pub fn next<'a>(&mut self, data: &mut &'a [u8]) -> Option<Piece<'a>> {
+        while !data.is_empty() {
+            if self.remaining > 0 {
+                let n = cmp::min(self.remaining, data.len() as u64) as usize;
+                self.remaining -= n as u64;
+                let (payload, rest) = data.split_at(n);
+                *data = rest;
+                return Some(Piece::Payload(payload));
+            }
+            self.header[self.have] = data[0];
+            self.have += 1;
+            *data = &data[1..];
+            if Some(self.have) != self.header_len() {
+                continue;
+            }
+            let frame = Frame {
+                first: self.header[0],
+                len: self.payload_len(),
+                mask: self.mask(),
+            };
+            self.have = 0;
+            self.remaining = frame.len;
+            return Some(Piece::Header(frame));
+        }
+        None
+    }
+
+    // Total header length, once enough of it is known
+    fn header_len(&self) -> Option<usize> {
+        if self.have < 2 {
+            return None;
+        }
+        let mask = if self.header[1] & 0x80 != 0 { 4 } else { 0 };
+        let extended = match self.header[1] & 0x7f {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        Some(2 + extended + mask)
+    }
+
+    fn payload_len(&self) -> u64 {
+        match self.header[1] & 0x7f {
+            126 => self.header[2..4].iter().fold(0, |n, b| n << 8 | *b as u64),
+            127 => self.header[2..10].iter().fold(0, |n, b| n << 8 | *b as u64),
+            n => n as u64,
+        }
+    }
+
+    // Masking key, which ends the header
+    fn mask(&self) -> Option<[u8; 4]> {
+        if self.header[1] & 0x80 == 0 {
+            return None;
+        }
+        let mut mask = [0; 4];
+        mask.copy_from_slice(&self.header[self.have - 4..self.have]);
+        Some(mask)
+    }
+}
+
+impl Frame {
+    /// Appends this header to `out`, with the shortest length encoding
+    pub fn write(&self, out: &mut Vec<u8>) {
+        let mask_bit = if self.mask.is_some() { 0x80 } else { 0 };
+        out.push(self.first);
+        if self.len < 126 {
+            out.push(mask_bit | self.len as u8);
+        } else if self.len <= 0xffff {
+            out.push(mask_bit | 126);
+            out.extend_from_slice(&[(self.len >> 8) as u8, self.len as u8]);
+        } else {
+            out.push(mask_bit | 127);
+            out.extend((0..8).rev().map(|i| (self.len >> (i * 8)) as u8));
+        }
+        if let Some(mask) = self.mask {
+            out.extend_from_slice(&mask);
+        }
+    }
+}
+
+fn too_big() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "websocket message too big")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{frame_within_limits, FrameCursor};
+
+    // Feeds `data` through `cursor`, collecting the frames seen
+    fn frames(cursor: &mut FrameCursor, data: &[u8]) -> Vec<(u8, u64)> {
+        let mut seen = Vec::new();
+        assert!(cursor.advance(data, |first, len| {
+            seen.push((first, len));
+            true
+        }));
+        seen
+    }
+
+    #[test]
+    fn masked_frames() {
+        let mut cursor = FrameCursor::new();
+        let mut data = vec![0x81, 0x85, 1, 2, 3, 4];
+        data.extend_from_slice(b"hello");
+        data.extend_from_slice(&[0x88, 0x80, 1, 2, 3, 4]);
+        assert_eq!(frames(&mut cursor, &data), vec![(0x81, 5), (0x88, 0)]);
+        assert!(cursor.at_boundary());
+    }
+
+    #[test]
+    fn extended_lengths() {
+        let mut cursor = FrameCursor::new();
+        let mut data = vec![0x82, 0x7e, 0x01, 0x00];
+        data.extend_from_slice(&[0; 256]);
+        assert_eq!(frames(&mut cursor, &data), vec![(0x82, 256)]);
+        assert!(cursor.at_boundary());
+
+        let data = [0x02, 0xff, 0, 0, 0, 1, 0, 0, 0, 0, 1, 2, 3, 4];
+        assert_eq!(frames(&mut cursor, &data), vec![(0x02, 1 << 32)]);
+        assert!(!cursor.at_boundary());
+    }
+
+    #[test]
+    fn split_across_reads() {
+        let mut cursor = FrameCursor::new();
+        let data = [0x81, 0xfe, 0x00, 0x02, 1, 2, 3, 4, b'h', b'i', 0x80, 0x00];
+        let mut seen = Vec::new();
+        for byte in data.iter() {
+            seen.extend(frames(&mut cursor, &[*byte]));
+            if seen.len() == 1 {
+                break;
+            }
+            assert!(!cursor.at_boundary());
+        }
+        assert_eq!(seen, vec![(0x81, 2)]);
+        assert_eq!(frames(&mut cursor, &data[8..]), vec![(0x80, 0)]);
+        assert!(cursor.at_boundary());
+    }
+
+    #[test]
+    fn stops_when_refused() {
+        let mut cursor = FrameCursor::new();
+        let data = [0x81, 0x01, b'a', 0x81, 0x01, b'b'];
+        let mut calls = 0;
+        assert!(!cursor.advance(&data, |_, _| {
+            calls += 1;
+            false
+        }));
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn message_limits() {
+        let max = u64::max_value();
+        let mut message_len = 0;
+        assert!(frame_within_limits(0x01, 60, &mut message_len, max, 100));
+        assert!(frame_within_limits(0x80, 40, &mut message_len, max, 100));
+        assert_eq!(message_len, 100);
+        assert!(!frame_within_limits(0x80, 1, &mut message_len, max, 100));
+        assert!(!frame_within_limits(0x82, 101, &mut message_len, max, 100));
+        assert!(!frame_within_limits(0x81, 11, &mut message_len, 10, max));
+    }
+
+    #[test]
+    fn control_frame_limits() {
+        let max = u64::max_value();
+        let mut message_len = 0;
+        assert!(frame_within_limits(0x89, 125, &mut message_len, max, max));
+        // However long a ping claims to be, without any limits configured
+        assert!(!frame_within_limits(0x89, 126, &mut message_len, max, max));
+        assert!(!frame_within_limits(0x89, 1 << 32, &mut message_len, max, max));
+        assert!(!frame_within_limits(0x8a, 20, &mut message_len, max, 10));
+        // Control frames don't count towards the message they interrupt
+        assert!(frame_within_limits(0x01, 50, &mut message_len, max, 100));
+        assert!(frame_within_limits(0x89, 10, &mut message_len, max, 100));
+        assert_eq!(message_len, 50);
+    }
+}
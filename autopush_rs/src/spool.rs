@@ -0,0 +1,553 @@
+//! Durable spool for notifications that couldn't be stored
+//!
+//! When a client goes away its unacked direct notifications are handed to
+//! Python to be stored. Should that fail, because Python or DynamoDB is
+//! unavailable, they're appended to an on-disk spool instead: one JSON record
+//! per line, synced to disk before moving on.
+//!
+//! The spool is an append-only log. Next to it a checkpoint file (the spool's
+//! path with an `offset` extension) records how far into the log records have
+//! been stored. The log is replayed in the background while the server runs:
+//! the record at the checkpoint is stored, then the checkpoint moved past it,
+//! backing off while stores (or the spool itself) keep failing. Once every
+//! record has been stored the log is emptied again. Records therefore survive
+//! restarts until they've made it to storage.
+//!
+//! All of the file I/O, syncs included, happens on a thread of its own so the
+//! tokio thread never waits on the disk.
+//!
+//! The spool may be capped at `spool_max_bytes`, records that would take it
+//! past that being refused. Those notifications are lost, and counted by the
+//! `spool.dropped` metric.
+//!
+//! The number of spooled records is reported as the `spool.depth` gauge.
+
+use std::cmp;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::Duration;
+
+use cadence::prelude::*;
+use futures::future::{self, Loop};
+use futures::sync::oneshot;
+use futures::Future;
+use serde_json;
+use tokio_core::reactor::Timeout;
+
+use errors::*;
+use protocol::Notification;
+use server::Server;
+
+const MIN_REPLAY_DELAY: u64 = 1;
+const MAX_REPLAY_DELAY: u64 = 300;
+
+/// Notifications that failed to be stored for a user
+#[derive(Serialize, Deserialize, Clone)]
+struct SpooledStore {
+    uaid: String,
+    message_month: String,
+    messages: Vec<Notification>,
+}
+
+/// Requests for the spool's I/O thread, each replied to once done
+enum Op {
+    /// Append a serialized record, syncing it to disk
+    Append(Vec<u8>, oneshot::Sender<Result<()>>),
+    /// Read the oldest record not yet stored, along with its length in the log
+    Front(oneshot::Sender<Result<Option<(SpooledStore, u64)>>>),
+    /// Move the checkpoint past the oldest record, `u64` bytes long
+    Pop(u64, oneshot::Sender<Result<()>>),
+}
+
+/// A handle to the spool, its file I/O happening on another thread
+pub struct Spool {
+    ops: Option<mpsc::Sender<Op>>,
+    depth: Arc<AtomicUsize>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl Spool {
+    /// Opens the spool at `path`, creating it if it doesn't exist yet and
+    /// picking up from the checkpoint of a previous run. Appends that would
+    /// grow it past `max_bytes` fail with `SpoolFull`.
+    pub fn open(path: &Path, max_bytes: Option<u64>) -> Result<Spool> {
+        let mut log = Log::open(path, max_bytes)?;
+        let depth = Arc::new(AtomicUsize::new(log.depth));
+        let (tx, rx) = mpsc::channel();
+        let depth2 = depth.clone();
+        let thread = thread::Builder::new()
+            .name("spool".to_string())
+            .spawn(move || {
+                // The depth is updated before replying, so it's current by
+                // the time the reply is seen
+                for op in rx {
+                    match op {
+                        Op::Append(line, tx) => {
+                            let res = log.append(&line);
+                            depth2.store(log.depth, Ordering::SeqCst);
+                            drop(tx.send(res));
+                        }
+                        Op::Front(tx) => {
+                            let res = log.front();
+                            depth2.store(log.depth, Ordering::SeqCst);
+                            drop(tx.send(res));
+                        }
+                        Op::Pop(len, tx) => {
+                            let res = log.pop(len);
+                            depth2.store(log.depth, Ordering::SeqCst);
+                            drop(tx.send(res));
+                        }
+                    }
+                }
+            })
+            .chain_err(|| "failed to spawn spool thread")?;
+        Ok(Spool {
+            ops: Some(tx),
+            depth: depth,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn depth(&self) -> usize {
+        self.depth.load(Ordering::SeqCst)
+    }
+
+    fn append(&self, record: &SpooledStore) -> MyFuture<()> {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(e) => return Box::new(future::err(e.into())),
+        };
+        line.push(b'\n');
+        self.request(|tx| Op::Append(line, tx))
+    }
+
+    fn front(&self) -> MyFuture<Option<(SpooledStore, u64)>> {
+        self.request(Op::Front)
+    }
+
+    /// Drops the oldest record, `len` bytes long, once it's been stored.
+    fn pop_front(&self, len: u64) -> MyFuture<()> {
+        self.request(|tx| Op::Pop(len, tx))
+    }
+
+    fn request<T, F>(&self, op: F) -> MyFuture<T>
+    where
+        T: 'static,
+        F: FnOnce(oneshot::Sender<Result<T>>) -> Op,
+    {
+        let (tx, rx) = oneshot::channel();
+        if self.ops.as_ref().unwrap().send(op(tx)).is_err() {
+            return Box::new(future::err("spool thread exited".into()));
+        }
+        Box::new(rx.then(|res| match res {
+            Ok(res) => res,
+            Err(_) => Err("spool thread exited".into()),
+        }))
+    }
+}
+
+impl Drop for Spool {
+    fn drop(&mut self) {
+        // Let anything still queued up reach the disk before going away
+        drop(self.ops.take());
+        if let Some(thread) = self.thread.take() {
+            drop(thread.join());
+        }
+    }
+}
+
+/// The spool's files, only ever touched by its I/O thread
+struct Log {
+    file: File,
+    checkpoint: PathBuf,
+    // Where the oldest record not yet stored starts
+    offset: u64,
+    len: u64,
+    // Records from `offset` on
+    depth: usize,
+    max_bytes: Option<u64>,
+}
+
+impl Log {
+    fn open(path: &Path, max_bytes: Option<u64>) -> Result<Log> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+            .chain_err(|| "failed to open spool")?;
+        let checkpoint = path.with_extension("offset");
+        let mut offset: u64 = match File::open(&checkpoint) {
+            Ok(mut f) => {
+                let mut s = String::new();
+                f.read_to_string(&mut s)
+                    .chain_err(|| "failed to read spool checkpoint")?;
+                // Storing records twice is harmless, losing them isn't
+                s.trim().parse().unwrap_or_else(|e| {
+                    warn!("Replaying whole spool, invalid checkpoint: {}", e);
+                    0
+                })
+            }
+            Err(_) => 0,
+        };
+
+        // Find where each record ends, dropping a record left half written
+        // by a crash so that later appends start on a line of their own
+        let mut len = 0;
+        let mut ends = Vec::new();
+        {
+            let mut reader = BufReader::new(&file);
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                let n = reader
+                    .read_until(b'\n', &mut line)
+                    .chain_err(|| "failed to read spool")? as u64;
+                if n == 0 {
+                    break;
+                }
+                if line.last() != Some(&b'\n') {
+                    warn!("Dropping incomplete spool record");
+                    break;
+                }
+                len += n;
+                ends.push(len);
+            }
+        }
+        file.set_len(len).chain_err(|| "failed to truncate spool")?;
+
+        // The log's emptied before the checkpoint is reset, so one past the
+        // end means a crash in between
+        if offset > len {
+            offset = 0;
+        }
+        let depth = ends.iter().filter(|end| **end > offset).count();
+        file.seek(SeekFrom::Start(offset))
+            .chain_err(|| "failed to seek spool")?;
+        Ok(Log {
+            file: file,
+            checkpoint: checkpoint,
+            offset: offset,
+            len: len,
+            depth: depth,
+            max_bytes: max_bytes,
+        })
+    }
+
+    fn append(&mut self, line: &[u8]) -> Result<()> {
+        // Stored records still count until the log is emptied, as they
+        // still take up room on disk
+        if let Some(max) = self.max_bytes {
+            if self.len + line.len() as u64 > max {
+                return Err(ErrorKind::SpoolFull.into());
+            }
+        }
+        self.file
+            .write_all(line)
+            .chain_err(|| "failed to append to spool")?;
+        self.file.sync_data().chain_err(|| "failed to sync spool")?;
+        self.len += line.len() as u64;
+        self.depth += 1;
+        Ok(())
+    }
+
+    /// Reads the oldest record not yet stored, skipping past any that can't
+    /// be read back.
+    fn front(&mut self) -> Result<Option<(SpooledStore, u64)>> {
+        loop {
+            if self.offset >= self.len {
+                return Ok(None);
+            }
+            self.file
+                .seek(SeekFrom::Start(self.offset))
+                .chain_err(|| "failed to seek spool")?;
+            let mut line = Vec::new();
+            let n = BufReader::new(&self.file)
+                .read_until(b'\n', &mut line)
+                .chain_err(|| "failed to read spool")? as u64;
+            match serde_json::from_slice(&line) {
+                Ok(record) => return Ok(Some((record, n))),
+                Err(e) => {
+                    warn!("Skipping unreadable spool record: {}", e);
+                    self.pop(n)?;
+                }
+            }
+        }
+    }
+
+    fn pop(&mut self, len: u64) -> Result<()> {
+        self.offset += len;
+        self.depth -= 1;
+        if self.offset >= self.len {
+            // Everything's been stored, start the log over
+            self.file.set_len(0).chain_err(|| "failed to truncate spool")?;
+            self.file.sync_all().chain_err(|| "failed to sync spool")?;
+            self.offset = 0;
+            self.len = 0;
+        }
+        self.save_checkpoint()
+    }
+
+    fn save_checkpoint(&self) -> Result<()> {
+        let tmp = self.checkpoint.with_extension("offset.tmp");
+        {
+            let mut file = File::create(&tmp).chain_err(|| "failed to create spool checkpoint")?;
+            write!(file, "{}", self.offset).chain_err(|| "failed to write spool checkpoint")?;
+            file.sync_all()
+                .chain_err(|| "failed to sync spool checkpoint")?;
+        }
+        fs::rename(&tmp, &self.checkpoint).chain_err(|| "failed to replace spool checkpoint")
+    }
+}
+
+/// Stores `messages` for `uaid`, spooling them to disk if that fails.
+///
+/// Nothing is waited on, the store (and any spooling) happens in the
+/// background.
+pub fn store_or_spool(
+    srv: &Rc<Server>,
+    uaid: String,
+    message_month: String,
+    messages: Vec<Notification>,
+) {
+    let record = SpooledStore {
+        uaid: uaid.clone(),
+        message_month: message_month.clone(),
+        messages: messages.clone(),
+    };
+    let srv2 = srv.clone();
+    let store = srv.store_messages(uaid, message_month, messages).then(
+        move |res| -> Box<Future<Item = (), Error = ()>> {
+            let e = match res {
+                Ok(_) => {
+                    debug!("Finished saving unacked direct notifications");
+                    return Box::new(future::ok(()));
+                }
+                Err(e) => e,
+            };
+            let append = match srv2.spool {
+                Some(ref spool) => spool.append(&record),
+                None => {
+                    error!("Failed to save unacked direct notifications: {}", e);
+                    return Box::new(future::ok(()));
+                }
+            };
+            debug!("Spooling unacked direct notifications: {}", e);
+            Box::new(append.then(move |res| {
+                match res {
+                    Ok(()) => srv2.report_spool_depth(),
+                    Err(e) => if let ErrorKind::SpoolFull = *e.kind() {
+                        error!("Dropping unacked direct notifications, spool is full");
+                        srv2.metrics.incr("spool.dropped").ok();
+                    } else {
+                        error!("Failed to spool unacked direct notifications: {}", e);
+                    },
+                }
+                Ok::<(), ()>(())
+            }))
+        },
+    );
+    srv.handle.spawn(store);
+}
+
+impl Server {
+    fn report_spool_depth(&self) {
+        if let Some(ref spool) = self.spool {
+            self.metrics
+                .gauge("spool.depth", spool.depth() as u64)
+                .ok();
+        }
+    }
+}
+
+/// Replays the spool of `srv`, if any, in the background for as long as the
+/// server is running.
+///
+/// Failures, whether storing records or reading them back, only make replay
+/// back off before trying again.
+pub fn replay(srv: &Rc<Server>) {
+    if srv.spool.is_none() {
+        return;
+    }
+    srv.report_spool_depth();
+    let handle = srv.handle.clone();
+    let srv2 = srv.clone();
+    let replay = future::loop_fn(MIN_REPLAY_DELAY, move |delay| {
+        let srv = srv2.clone();
+        let wait: MyFuture<()> = match Timeout::new(Duration::from_secs(delay), &handle) {
+            Ok(timeout) => Box::new(timeout.chain_err(|| "spool timer failed")),
+            Err(e) => Box::new(future::err(e.into())),
+        };
+        wait.and_then(move |()| drain(srv))
+            .then(move |res| -> Result<Loop<(), u64>> {
+                match res {
+                    Ok(true) => return Ok(Loop::Continue(MIN_REPLAY_DELAY)),
+                    Ok(false) => {}
+                    Err(e) => error!("Failed to replay spool: {}", e),
+                }
+                Ok(Loop::Continue(cmp::min(delay * 2, MAX_REPLAY_DELAY)))
+            })
+    });
+    srv.handle.spawn(replay.map_err(|e| {
+        error!("Spool replay stopped: {}", e);
+    }));
+}
+
+/// Stores spooled records oldest first until either none are left (resolving
+/// to `true`) or a store fails (resolving to `false`).
+fn drain(srv: Rc<Server>) -> MyFuture<bool> {
+    Box::new(future::loop_fn(srv, |srv| {
+        let front = srv.spool.as_ref().unwrap().front();
+        front.and_then(move |front| -> MyFuture<Loop<bool, Rc<Server>>> {
+            let (record, len) = match front {
+                Some(front) => front,
+                None => return Box::new(future::ok(Loop::Break(true))),
+            };
+            let store = srv.store_messages(record.uaid, record.message_month, record.messages);
+            Box::new(store.then(move |res| -> MyFuture<Loop<bool, Rc<Server>>> {
+                if let Err(e) = res {
+                    debug!("Failed to replay spooled notifications: {}", e);
+                    return Box::new(future::ok(Loop::Break(false)));
+                }
+                let popped = srv.spool.as_ref().unwrap().pop_front(len);
+                Box::new(popped.map(move |()| {
+                    srv.report_spool_depth();
+                    srv.metrics.incr("spool.replayed").ok();
+                    Loop::Continue(srv)
+                }))
+            }))
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use super::{Log, SpooledStore};
+
+    fn spool_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("autopush-spool-{}", name));
+        drop(fs::remove_dir_all(&dir));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("spool.log")
+    }
+
+    fn record(uaid: &str) -> Vec<u8> {
+        let record = SpooledStore {
+            uaid: uaid.to_string(),
+            message_month: "message_2017_11".to_string(),
+            messages: Vec::new(),
+        };
+        let mut line = ::serde_json::to_vec(&record).unwrap();
+        line.push(b'\n');
+        line
+    }
+
+    fn front_uaid(log: &mut Log) -> Option<(String, u64)> {
+        log.front()
+            .unwrap()
+            .map(|(record, len)| (record.uaid, len))
+    }
+
+    #[test]
+    fn replays_in_order() {
+        let path = spool_path("order");
+        let mut log = Log::open(&path, None).unwrap();
+        assert!(front_uaid(&mut log).is_none());
+        log.append(&record("a")).unwrap();
+        log.append(&record("b")).unwrap();
+        assert_eq!(log.depth, 2);
+
+        let (uaid, len) = front_uaid(&mut log).unwrap();
+        assert_eq!(uaid, "a");
+        // Reading doesn't consume anything until it's popped
+        assert_eq!(front_uaid(&mut log).unwrap().0, "a");
+        log.pop(len).unwrap();
+        assert_eq!(log.depth, 1);
+        assert_eq!(front_uaid(&mut log).unwrap().0, "b");
+    }
+
+    #[test]
+    fn checkpoint_survives_reopening() {
+        let path = spool_path("reopen");
+        {
+            let mut log = Log::open(&path, None).unwrap();
+            log.append(&record("a")).unwrap();
+            log.append(&record("b")).unwrap();
+            let (_, len) = front_uaid(&mut log).unwrap();
+            log.pop(len).unwrap();
+        }
+        let mut log = Log::open(&path, None).unwrap();
+        assert_eq!(log.depth, 1);
+        assert_eq!(front_uaid(&mut log).unwrap().0, "b");
+    }
+
+    #[test]
+    fn empties_once_drained() {
+        let path = spool_path("drained");
+        let mut log = Log::open(&path, None).unwrap();
+        log.append(&record("a")).unwrap();
+        let (_, len) = front_uaid(&mut log).unwrap();
+        log.pop(len).unwrap();
+        assert_eq!(log.depth, 0);
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+
+        log.append(&record("b")).unwrap();
+        drop(log);
+        let mut log = Log::open(&path, None).unwrap();
+        assert_eq!(front_uaid(&mut log).unwrap().0, "b");
+    }
+
+    #[test]
+    fn drops_incomplete_and_unreadable_records() {
+        let path = spool_path("damaged");
+        {
+            let mut file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)
+                .unwrap();
+            file.write_all(b"not json\n").unwrap();
+            file.write_all(&record("a")).unwrap();
+            file.write_all(b"{\"uaid\":").unwrap();
+        }
+        let mut log = Log::open(&path, None).unwrap();
+        assert_eq!(log.depth, 2);
+        log.append(&record("b")).unwrap();
+
+        let (uaid, len) = front_uaid(&mut log).unwrap();
+        assert_eq!(uaid, "a");
+        log.pop(len).unwrap();
+        assert_eq!(front_uaid(&mut log).unwrap().0, "b");
+        assert_eq!(log.depth, 1);
+    }
+
+    #[test]
+    fn refuses_records_past_max_bytes() {
+        let path = spool_path("full");
+        let max = record("a").len() as u64 * 2;
+        let mut log = Log::open(&path, Some(max)).unwrap();
+        log.append(&record("a")).unwrap();
+        log.append(&record("b")).unwrap();
+        assert!(log.append(&record("c")).is_err());
+        assert_eq!(log.depth, 2);
+
+        // Room is only made once the log has been emptied
+        let (_, len) = front_uaid(&mut log).unwrap();
+        log.pop(len).unwrap();
+        assert!(log.append(&record("c")).is_err());
+        let (_, len) = front_uaid(&mut log).unwrap();
+        log.pop(len).unwrap();
+        log.append(&record("c")).unwrap();
+        assert_eq!(front_uaid(&mut log).unwrap().0, "c");
+    }
+}